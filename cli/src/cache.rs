@@ -0,0 +1,139 @@
+use lru::LruCache;
+
+use std::{
+    hash::{BuildHasher, Hash, RandomState},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// The smallest number of entries a shard of the cache holds
+const MIN_SHARD_CAPACITY: usize = 1024;
+
+/// The most shards a cache is split into
+const MAX_SHARDS: usize = 64;
+
+/// A bounded least recently used cache that can be shared across threads
+/// Keys are split across shards by their hash, each behind its own lock, so threads looking up
+/// different keys rarely wait on each other. Least recently used entries are evicted per shard.
+/// Counts its hits and misses so they can be reported at the end of a run.
+/// A capacity of zero disables the cache, every lookup is then computed and counted as a miss.
+#[derive(Debug)]
+pub(crate) struct SharedCache<K: Hash + Eq, V: Clone> {
+    shards: Vec<Mutex<LruCache<K, V>>>,
+    hasher: RandomState,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<K: Hash + Eq, V: Clone> SharedCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let shard_count = (capacity / MIN_SHARD_CAPACITY).clamp(1, MAX_SHARDS);
+        SharedCache {
+            shards: NonZeroUsize::new(capacity.div_ceil(shard_count))
+                .map(|c| {
+                    (0..shard_count)
+                        .map(|_| Mutex::new(LruCache::new(c)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            hasher: RandomState::new(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether values are kept between lookups
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.shards.is_empty()
+    }
+
+    /// The shard a key is cached in
+    fn shard(&self, key: &K) -> Option<&Mutex<LruCache<K, V>>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        Some(&self.shards[index])
+    }
+
+    /// Get the value for a key, computing and caching it with `f` if it is not cached
+    /// The lock is not held while computing, so two threads can compute the same value.
+    pub(crate) fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V {
+        let Some(entries) = self.shard(&key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return f();
+        };
+        if let Some(value) = lock(entries).get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return value.clone();
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = f();
+        lock(entries).put(key, value.clone());
+        value
+    }
+
+    /// The number of lookups that were and were not already cached
+    pub(crate) fn stats(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Lock the cache, a panic while it was held cannot leave it inconsistent so poisoning is ignored
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_evicted() {
+        let cache = SharedCache::new(2);
+        assert_eq!(cache.get_or_insert_with("A", || 1), 1);
+        assert_eq!(cache.get_or_insert_with("B", || 2), 2);
+        // cached values are returned without computing
+        assert_eq!(cache.get_or_insert_with("A", || 10), 1);
+        // B is evicted as the least recently used
+        assert_eq!(cache.get_or_insert_with("C", || 3), 3);
+        assert_eq!(cache.get_or_insert_with("B", || 20), 20);
+        assert_eq!(cache.get_or_insert_with("A", || 30), 30);
+        assert_eq!(cache.stats(), (1, 5));
+    }
+
+    #[test]
+    fn test_disabled_cache() {
+        let cache = SharedCache::new(0);
+        assert!(!cache.is_enabled());
+        assert_eq!(cache.get_or_insert_with("A", || 1), 1);
+        assert_eq!(cache.get_or_insert_with("A", || 2), 2);
+        assert_eq!(cache.stats(), (0, 2));
+    }
+
+    #[test]
+    fn test_sharded_cache() {
+        let cache = SharedCache::new(100_000);
+        assert_eq!(cache.shards.len(), MAX_SHARDS);
+        assert_eq!(SharedCache::<usize, usize>::new(4096).shards.len(), 4);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for key in 0..1000 {
+                        assert_eq!(cache.get_or_insert_with(key, || key * 2), key * 2);
+                    }
+                });
+            }
+        });
+        let (hits, misses) = cache.stats();
+        assert_eq!(hits + misses, 4000);
+        assert!(misses >= 1000);
+        assert_eq!(cache.get_or_insert_with(7, || 0), 14);
+    }
+}
@@ -0,0 +1,163 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use std::{borrow::Cow, str::FromStr};
+
+use crate::{clean_text, strip_punctuation, tokenize, tokenize_by, Error, Result, Token};
+
+/// How text, search terms and exclusion list entries are cleaned before they are compared
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextCleaning {
+    /// Keep ASCII letters, digits and hyphens, anything else becomes a space
+    #[default]
+    Ascii,
+    /// Normalize to NFKD, fold away accents, map dashes to hyphens and uppercase with Unicode rules,
+    /// keeping letters and digits from any script
+    Unicode,
+}
+
+impl TextCleaning {
+    /// Names of the text cleaning modes, as used on the command line
+    pub const NAMES: [&'static str; 2] = ["ascii", "unicode"];
+
+    /// Clean and uppercase a string, see [clean_text]
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::TextCleaning;
+    ///
+    /// assert_eq!(TextCleaning::Ascii.clean("cocaína"), "COCA NA");
+    /// assert_eq!(TextCleaning::Unicode.clean("cocaína"), "COCAINA");
+    /// assert_eq!(TextCleaning::Unicode.clean("“naïve” 6‐MAM"), "NAIVE  6-MAM");
+    /// ```
+    pub fn clean(&self, s: &str) -> String {
+        match self {
+            TextCleaning::Ascii => clean_text(s),
+            TextCleaning::Unicode => strip_non_words(&fold_text(s, true).0),
+        }
+    }
+
+    /// Clean a string without changing its case, see [strip_punctuation]
+    pub fn strip_punctuation(&self, s: &str) -> String {
+        match self {
+            TextCleaning::Ascii => strip_punctuation(s),
+            TextCleaning::Unicode => strip_non_words(&fold_text(s, false).0),
+        }
+    }
+
+    /// Split normalized text into words, see [tokenize]
+    pub fn tokenize<'a>(&self, s: &'a str) -> Vec<Token<'a>> {
+        match self {
+            TextCleaning::Ascii => tokenize(s),
+            TextCleaning::Unicode => tokenize_by(s, is_unicode_word),
+        }
+    }
+
+    /// Normalize text ready to be split into words, optionally uppercasing it
+    /// Returns the normalized text and, if the normalization moved any characters,
+    /// the character offset in `s` of each character of the normalized text.
+    pub(crate) fn normalize<'a>(
+        &self,
+        s: &'a str,
+        uppercase: bool,
+    ) -> (Cow<'a, str>, Option<Vec<usize>>) {
+        match self {
+            // ascii uppercasing keeps every character in place
+            TextCleaning::Ascii if uppercase => (Cow::Owned(s.to_ascii_uppercase()), None),
+            TextCleaning::Ascii => (Cow::Borrowed(s), None),
+            TextCleaning::Unicode => {
+                let (folded, offsets) = fold_text(s, uppercase);
+                (Cow::Owned(folded), Some(offsets))
+            }
+        }
+    }
+}
+
+impl FromStr for TextCleaning {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ascii" => Ok(TextCleaning::Ascii),
+            "unicode" => Ok(TextCleaning::Unicode),
+            _ => Err(Error::InvalidOption(format!("Unknown text cleaning {}", s))),
+        }
+    }
+}
+
+/// Whether a character is part of a word in unicode cleaned text
+fn is_unicode_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '-'
+}
+
+/// Replace everything but letters, digits and hyphens with spaces
+fn strip_non_words(s: &str) -> String {
+    s.replace(|c: char| !is_unicode_word(c), " ")
+        .trim()
+        .to_string()
+}
+
+/// Function to fold text to its NFKD form without accents, mapping dashes to hyphens
+/// Each character is folded on its own so the character offset in `s` that every folded
+/// character came from can be returned alongside the folded text.
+fn fold_text(s: &str, uppercase: bool) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(s.len());
+    let mut offsets = Vec::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        let c = match c {
+            // soft hyphens are invisible so are dropped rather than splitting a word
+            '\u{ad}' => continue,
+            '\u{2010}'..='\u{2015}' | '\u{2212}' | '\u{fe58}' | '\u{fe63}' | '\u{ff0d}' => '-',
+            c => c,
+        };
+        for d in std::iter::once(c).nfkd().filter(|d| !is_combining_mark(*d)) {
+            if uppercase {
+                for u in d.to_uppercase() {
+                    folded.push(u);
+                    offsets.push(i);
+                }
+            } else {
+                folded.push(d);
+                offsets.push(i);
+            }
+        }
+    }
+    (folded, offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_text_offsets() {
+        let (folded, offsets) = fold_text("ﬁ café—straße", true);
+        assert_eq!(folded, "FI CAFE-STRASSE");
+        assert_eq!(offsets.len(), folded.chars().count());
+        // both letters of the ligature come from the first character
+        assert_eq!(&offsets[..3], &[0, 0, 1]);
+        assert_eq!(offsets[folded.find('-').unwrap()], 6);
+        assert_eq!(*offsets.last().unwrap(), 12);
+    }
+
+    #[test]
+    fn test_unicode_tokenize() {
+        let (folded, _) = TextCleaning::Unicode.normalize("Intoxicación por cocaína", true);
+        let words = TextCleaning::Unicode
+            .tokenize(&folded)
+            .iter()
+            .map(|t| t.text)
+            .collect::<Vec<_>>();
+        assert_eq!(words, vec!["INTOXICACION", "POR", "COCAINA"]);
+        assert_eq!(
+            TextCleaning::Unicode.strip_punctuation("Fentanyl, Éter"),
+            "Fentanyl  Eter"
+        );
+    }
+
+    #[test]
+    fn test_text_cleaning_from_str() {
+        for name in TextCleaning::NAMES {
+            assert!(name.parse::<TextCleaning>().is_ok());
+        }
+        assert!("utf8".parse::<TextCleaning>().is_err());
+    }
+}
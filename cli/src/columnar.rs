@@ -0,0 +1,312 @@
+use arrow_array::{
+    builder::{BooleanBuilder, Float64Builder, ListBuilder, StringBuilder, UInt64Builder},
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+
+use std::sync::Arc;
+
+use crate::{
+    error::ErrorContext, metadata_tags, FinishWrite, MatchWriter, RecordOutput, Result,
+    SearchOutput,
+};
+
+/// Number of matches buffered before a record batch is written
+const MATCHES_PER_BATCH: usize = 8192;
+
+/// The arrow schema used for the columnar output formats
+/// Kept columns are appended as nullable Utf8 columns.
+pub fn match_schema(kept_columns: &[String]) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("row_id", DataType::Utf8, false),
+        Field::new("search_term", DataType::Utf8, false),
+        Field::new("matched_term", DataType::Utf8, false),
+        Field::new("edits", DataType::UInt64, false),
+        Field::new("similarity_score", DataType::Float64, false),
+        Field::new("search_field", DataType::Utf8, false),
+        Field::new(
+            "metadata",
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+            false,
+        ),
+        Field::new("start", DataType::UInt64, false),
+        Field::new("end", DataType::UInt64, false),
+        Field::new("context", DataType::Utf8, true),
+        Field::new("negated", DataType::Boolean, false),
+        Field::new("uncertain", DataType::Boolean, false),
+        Field::new("canonical", DataType::Utf8, false),
+    ];
+    fields.extend(
+        kept_columns
+            .iter()
+            .map(|c| Field::new(c.as_str(), DataType::Utf8, true)),
+    );
+    Arc::new(Schema::new(fields))
+}
+
+/// Column builders for a batch of matches
+#[derive(Debug)]
+struct MatchBatchBuilder {
+    row_id: StringBuilder,
+    search_term: StringBuilder,
+    matched_term: StringBuilder,
+    edits: UInt64Builder,
+    similarity_score: Float64Builder,
+    search_field: StringBuilder,
+    metadata: ListBuilder<StringBuilder>,
+    start: UInt64Builder,
+    end: UInt64Builder,
+    context: StringBuilder,
+    negated: BooleanBuilder,
+    uncertain: BooleanBuilder,
+    canonical: StringBuilder,
+    kept_columns: Vec<StringBuilder>,
+    len: usize,
+}
+
+impl MatchBatchBuilder {
+    fn new(kept_columns: usize) -> Self {
+        MatchBatchBuilder {
+            row_id: StringBuilder::new(),
+            search_term: StringBuilder::new(),
+            matched_term: StringBuilder::new(),
+            edits: UInt64Builder::new(),
+            similarity_score: Float64Builder::new(),
+            search_field: StringBuilder::new(),
+            metadata: ListBuilder::new(StringBuilder::new()),
+            start: UInt64Builder::new(),
+            end: UInt64Builder::new(),
+            context: StringBuilder::new(),
+            negated: BooleanBuilder::new(),
+            uncertain: BooleanBuilder::new(),
+            canonical: StringBuilder::new(),
+            kept_columns: (0..kept_columns).map(|_| StringBuilder::new()).collect(),
+            len: 0,
+        }
+    }
+
+    fn append(&mut self, record: &RecordOutput, m: &SearchOutput) {
+        self.row_id.append_value(m.row_id);
+        self.search_term.append_value(m.search_term);
+        self.matched_term.append_value(m.matched_term);
+        self.edits.append_value(m.edits as u64);
+        self.similarity_score.append_value(m.similarity_score);
+        self.search_field.append_value(m.search_field);
+        if let Some(metadata) = m.metadata {
+            for tag in metadata_tags(metadata) {
+                self.metadata.values().append_value(tag);
+            }
+        }
+        self.metadata.append(true);
+        self.start.append_value(m.start as u64);
+        self.end.append_value(m.end as u64);
+        self.context.append_option(m.context);
+        self.negated.append_value(m.negated);
+        self.uncertain.append_value(m.uncertain);
+        self.canonical.append_value(m.canonical);
+        for (builder, value) in self.kept_columns.iter_mut().zip(record.kept_columns) {
+            builder.append_value(value);
+        }
+        self.len += 1;
+    }
+
+    /// Build a record batch from the buffered matches, resetting the builders
+    fn finish(&mut self, schema: &SchemaRef) -> Result<RecordBatch> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.row_id.finish()),
+            Arc::new(self.search_term.finish()),
+            Arc::new(self.matched_term.finish()),
+            Arc::new(self.edits.finish()),
+            Arc::new(self.similarity_score.finish()),
+            Arc::new(self.search_field.finish()),
+            Arc::new(self.metadata.finish()),
+            Arc::new(self.start.finish()),
+            Arc::new(self.end.finish()),
+            Arc::new(self.context.finish()),
+            Arc::new(self.negated.finish()),
+            Arc::new(self.uncertain.finish()),
+            Arc::new(self.canonical.finish()),
+        ];
+        for builder in &mut self.kept_columns {
+            columns.push(Arc::new(builder.finish()));
+        }
+        self.len = 0;
+        RecordBatch::try_new(schema.clone(), columns).writer_context("Unable to build record batch")
+    }
+}
+
+/// The underlying columnar file writer
+enum ColumnarFile<W: FinishWrite + Send> {
+    Parquet(ArrowWriter<W>),
+    Ipc(arrow_ipc::writer::FileWriter<W>),
+}
+
+/// Writes matches as typed columns to a Parquet or Arrow IPC file
+pub struct ColumnarMatchWriter<W: FinishWrite + Send> {
+    schema: SchemaRef,
+    batch: MatchBatchBuilder,
+    file: ColumnarFile<W>,
+}
+
+impl<W: FinishWrite + Send> ColumnarMatchWriter<W> {
+    /// Create a writer for a zstd compressed Parquet file
+    pub fn parquet(output: W, kept_columns: &[String]) -> Result<Self> {
+        let schema = match_schema(kept_columns);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        let writer = ArrowWriter::try_new(output, schema.clone(), Some(properties))
+            .writer_context("Unable to initialize parquet writer")?;
+        Ok(ColumnarMatchWriter {
+            schema,
+            batch: MatchBatchBuilder::new(kept_columns.len()),
+            file: ColumnarFile::Parquet(writer),
+        })
+    }
+
+    /// Create a writer for an Arrow IPC file
+    pub fn arrow_ipc(output: W, kept_columns: &[String]) -> Result<Self> {
+        let schema = match_schema(kept_columns);
+        let writer = arrow_ipc::writer::FileWriter::try_new(output, &schema)
+            .writer_context("Unable to initialize arrow writer")?;
+        Ok(ColumnarMatchWriter {
+            schema,
+            batch: MatchBatchBuilder::new(kept_columns.len()),
+            file: ColumnarFile::Ipc(writer),
+        })
+    }
+
+    /// Write the buffered matches as a record batch
+    fn flush_batch(&mut self) -> Result<()> {
+        if self.batch.len == 0 {
+            return Ok(());
+        }
+        let batch = self.batch.finish(&self.schema)?;
+        match &mut self.file {
+            ColumnarFile::Parquet(w) => w
+                .write(&batch)
+                .writer_context("Unable to write parquet batch"),
+            ColumnarFile::Ipc(w) => w
+                .write(&batch)
+                .writer_context("Unable to write arrow batch"),
+        }
+    }
+}
+
+impl<W: FinishWrite + Send> MatchWriter for ColumnarMatchWriter<W> {
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()> {
+        for m in matches {
+            self.batch.append(record, m);
+        }
+        if self.batch.len >= MATCHES_PER_BATCH {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush_batch()?;
+        let output = match &mut self.file {
+            ColumnarFile::Parquet(w) => {
+                w.finish().writer_context("Unable to finish parquet file")?;
+                w.inner_mut()
+            }
+            ColumnarFile::Ipc(w) => {
+                w.finish().writer_context("Unable to finish arrow file")?;
+                w.get_mut()
+            }
+        };
+        output.finish().writer_context("Unable to finish output")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::heroin_match;
+    use color_eyre::Result;
+
+    #[test]
+    fn test_batch_builder_typed_columns() -> Result<()> {
+        let metadata = Some("drug|opiate".to_string());
+        let m = SearchOutput {
+            row_id: "7",
+            metadata: &metadata,
+            ..heroin_match()
+        };
+        let kept_columns = vec!["AGE".to_string()];
+        let record = RecordOutput {
+            row_id: "7",
+            kept_columns: &["42".to_string()],
+        };
+        let mut builder = MatchBatchBuilder::new(kept_columns.len());
+        builder.append(&record, &m);
+        builder.append(
+            &record,
+            &SearchOutput {
+                metadata: &None,
+                ..m.clone()
+            },
+        );
+        let batch = builder.finish(&match_schema(&kept_columns))?;
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.num_columns(), 14);
+        assert_eq!(batch.schema().field(3).data_type(), &DataType::UInt64);
+        assert_eq!(batch.schema().field(13).name(), "AGE");
+        assert_eq!(builder.len, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parquet_and_ipc_round_trip() -> Result<()> {
+        let m = SearchOutput {
+            search_term: "COCAINE",
+            matched_term: "COCAIN",
+            edits: 1,
+            similarity_score: 0.97,
+            canonical: "COCAINE",
+            ..heroin_match()
+        };
+        let record = RecordOutput {
+            row_id: "1",
+            kept_columns: &[],
+        };
+
+        let path = std::env::temp_dir().join("drug_extraction_columnar_test.parquet");
+        {
+            let mut writer = ColumnarMatchWriter::parquet(std::fs::File::create(&path)?, &[])?;
+            writer.write_record(&record, &[m.clone(), m.clone()])?;
+            writer.write_record(
+                &RecordOutput {
+                    row_id: "2",
+                    ..record
+                },
+                &[],
+            )?;
+            writer.finish()?;
+        }
+        let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(
+            std::fs::File::open(&path)?,
+        )?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(reader.schema(), &match_schema(&[]));
+        let rows: usize = reader.build()?.map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 2);
+
+        let mut ipc_buf = Vec::new();
+        {
+            let mut writer = ColumnarMatchWriter::arrow_ipc(&mut ipc_buf, &[])?;
+            writer.write_record(&record, &[m])?;
+            writer.finish()?;
+        }
+        let reader = arrow_ipc::reader::FileReader::try_new(std::io::Cursor::new(ipc_buf), None)?;
+        let rows: usize = reader.map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 1);
+        Ok(())
+    }
+}
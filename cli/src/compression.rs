@@ -0,0 +1,243 @@
+use bzip2::{read::MultiBzDecoder, write::BzEncoder};
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Stdout, Write},
+    path::Path,
+};
+
+use crate::{
+    error::{ErrorContext, IoContext},
+    Result,
+};
+
+/// Compression formats supported for the dataset and the output file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Plain text
+    #[default]
+    None,
+    /// gzip, `.gz`
+    Gzip,
+    /// Zstandard, `.zst`
+    Zstd,
+    /// bzip2, `.bz2`
+    Bzip2,
+}
+
+impl Compression {
+    /// Detect compression from the extension of a path
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::Compression;
+    ///
+    /// assert_eq!(Compression::from_extension("records.csv.gz"), Compression::Gzip);
+    /// assert_eq!(Compression::from_extension("records.csv"), Compression::None);
+    /// ```
+    pub fn from_extension<P: AsRef<Path>>(p: P) -> Compression {
+        let extension = p
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("gz") | Some("gzip") => Compression::Gzip,
+            Some("zst") | Some("zstd") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+
+    /// Detect compression from the magic bytes at the start of a stream
+    pub fn from_magic(bytes: &[u8]) -> Compression {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if bytes.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Function to wrap a reader so that it is transparently decompressed
+/// Compression is detected from the extension of `path`, falling back to the magic bytes.
+pub fn decompress_reader<R: Read + 'static, P: AsRef<Path>>(
+    reader: R,
+    path: P,
+) -> Result<(Box<dyn Read>, Compression)> {
+    let mut reader = BufReader::new(reader);
+    let compression = match Compression::from_extension(path) {
+        Compression::None => {
+            Compression::from_magic(reader.fill_buf().io_context("Unable to read data file")?)
+        }
+        c => c,
+    };
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(
+            zstd::Decoder::with_buffer(reader).io_context("Unable to initialize zstd decoder")?,
+        ),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+    };
+    Ok((reader, compression))
+}
+
+/// A writer whose output is ended explicitly, so that an error writing the end of the output,
+/// such as the trailer of a compressed stream, is reported rather than lost when it is dropped
+pub trait FinishWrite: Write {
+    /// Flush the writer and end its output, nothing should be written afterwards
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl FinishWrite for Vec<u8> {}
+
+impl FinishWrite for File {}
+
+impl FinishWrite for Stdout {}
+
+impl<W: FinishWrite + ?Sized> FinishWrite for &mut W {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<W: FinishWrite + ?Sized> FinishWrite for Box<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for BufWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().finish()
+    }
+}
+
+/// A writer that compresses its output, see [compress_writer]
+/// The compressed stream is only complete once [FinishWrite::finish] has been called.
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Bzip2(BzEncoder<W>),
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::None(w) => w.write(buf),
+            CompressedWriter::Gzip(w) => w.write(buf),
+            CompressedWriter::Zstd(w) => w.write(buf),
+            CompressedWriter::Bzip2(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::None(w) => w.flush(),
+            CompressedWriter::Gzip(w) => w.flush(),
+            CompressedWriter::Zstd(w) => w.flush(),
+            CompressedWriter::Bzip2(w) => w.flush(),
+        }
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for CompressedWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::None(w) => w.finish(),
+            CompressedWriter::Gzip(w) => {
+                w.try_finish()?;
+                w.get_mut().finish()
+            }
+            CompressedWriter::Zstd(w) => {
+                w.do_finish()?;
+                w.get_mut().finish()
+            }
+            CompressedWriter::Bzip2(w) => {
+                w.try_finish()?;
+                w.get_mut().finish()
+            }
+        }
+    }
+}
+
+/// Function to wrap a writer so that its output is compressed
+/// The compressed stream has to be ended with [FinishWrite::finish].
+pub fn compress_writer<W: Write>(
+    writer: W,
+    compression: Compression,
+) -> Result<CompressedWriter<W>> {
+    Ok(match compression {
+        Compression::None => CompressedWriter::None(writer),
+        Compression::Gzip => {
+            CompressedWriter::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+        }
+        Compression::Zstd => CompressedWriter::Zstd(
+            zstd::Encoder::new(writer, 0).writer_context("Unable to initialize zstd encoder")?,
+        ),
+        Compression::Bzip2 => {
+            CompressedWriter::Bzip2(BzEncoder::new(writer, bzip2::Compression::default()))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_magic() {
+        assert_eq!(
+            Compression::from_magic(&[0x1f, 0x8b, 0x08]),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::from_magic(b"BZh91AY"), Compression::Bzip2);
+        assert_eq!(Compression::from_magic(b"ID,TEXT"), Compression::None);
+        assert_eq!(Compression::from_magic(&[]), Compression::None);
+    }
+
+    #[test]
+    fn test_finish_reports_trailer_errors() {
+        /// Accepts a fixed number of bytes then fails every write
+        struct FullDisk(usize);
+        impl Write for FullDisk {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+                }
+                let n = buf.len().min(self.0);
+                self.0 -= n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl FinishWrite for FullDisk {}
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Bzip2] {
+            let mut writer = compress_writer(FullDisk(16), compression).unwrap();
+            writer.write_all(b"ID,TEXT\n1,fentanyl\n").unwrap();
+            assert!(writer.finish().is_err(), "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn test_from_extension_case_insensitive() {
+        assert_eq!(Compression::from_extension("a.csv.ZST"), Compression::Zstd);
+        assert_eq!(Compression::from_extension("a.csv.bz2"), Compression::Bzip2);
+        assert_eq!(Compression::from_extension("-"), Compression::None);
+    }
+}
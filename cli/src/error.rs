@@ -0,0 +1,111 @@
+use std::io;
+
+/// Errors returned by the library
+/// The variants separate problems with the inputs a caller controls (columns, search terms, options)
+/// from failures reading the dataset or writing matches.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A file could not be opened, read or created
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// A column to search, keep or use as the id is not in the dataset header
+    #[error("Unable to find column {column}, the available columns are: {}", available.join(", "))]
+    MissingColumn {
+        column: String,
+        /// the cleaned column names of the dataset header
+        available: Vec<String>,
+    },
+    /// A row of the search terms file could not be loaded, `line` is the 1-based line of the file
+    #[error("Could not load search term from line {line}: {message}")]
+    TermParse { line: usize, message: String },
+    /// A search term has nothing left to match once cleaned, or a matching override out of range
+    #[error("Search term {term:?} is not valid: {message}")]
+    InvalidTerm { term: String, message: String },
+    /// A row of the negation triggers file could not be loaded, `line` is the 1-based line of the file
+    #[error("Could not load trigger from line {line}: {message}")]
+    TriggerParse { line: usize, message: String },
+    /// The header of the dataset could not be read
+    #[error("Unable to parse csv headers: {0}")]
+    HeaderRead(String),
+    /// A record of the dataset could not be read
+    #[error("Unable to read record {row}: {message}")]
+    RecordRead {
+        /// the 0-based index of the record, which is its `row_id` when there is no id column
+        row: usize,
+        message: String,
+    },
+    /// Matches could not be written to the output
+    #[error("{context}")]
+    Writer {
+        context: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// An option, algorithm name or threshold is not valid
+    #[error("{0}")]
+    InvalidOption(String),
+    /// The thread pool to search with could not be started
+    #[error("Unable to initialize thread pool")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+}
+
+/// Result type of the library
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Wrap errors from files and writers with a description of what failed, like `wrap_err`
+pub(crate) trait ErrorContext<T> {
+    /// Wrap the error as an [Error::Writer]
+    fn writer_context<C: Into<String>>(self, context: C) -> Result<T>;
+}
+
+impl<T, E: Into<Box<dyn std::error::Error + Send + Sync>>> ErrorContext<T>
+    for std::result::Result<T, E>
+{
+    fn writer_context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|e| Error::Writer {
+            context: context.into(),
+            source: e.into(),
+        })
+    }
+}
+
+/// Wrap I/O errors with a description of what failed
+pub(crate) trait IoContext<T> {
+    /// Wrap the error as an [Error::Io]
+    fn io_context<C: Into<String>>(self, context: C) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context.into(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_messages() {
+        let e = Error::MissingColumn {
+            column: "TEXT".to_string(),
+            available: vec!["ID".to_string(), "NOTES".to_string()],
+        };
+        assert_eq!(
+            e.to_string(),
+            "Unable to find column TEXT, the available columns are: ID, NOTES"
+        );
+        let e = std::fs::read("/does/not/exist")
+            .io_context("Unable to read file")
+            .unwrap_err();
+        assert!(matches!(e, Error::Io { .. }));
+        assert!(std::error::Error::source(&e).is_some());
+    }
+}
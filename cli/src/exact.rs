@@ -0,0 +1,90 @@
+use aho_corasick::AhoCorasick;
+
+/// An Aho-Corasick automaton over search terms, finding every exact occurrence of every term
+/// in a single pass over the words of a piece of text
+/// Terms only match whole words, an occurrence must start and end on a word boundary.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExactMatcher {
+    automaton: Option<AhoCorasick>,
+    /// the search term index of each pattern
+    term_indices: Vec<usize>,
+    /// the number of words in each pattern
+    term_lens: Vec<usize>,
+}
+
+impl ExactMatcher {
+    /// Build the automaton from search terms paired with their indices
+    pub(crate) fn new<'t>(terms: impl IntoIterator<Item = (usize, &'t str)>) -> Self {
+        let mut matcher = ExactMatcher::default();
+        let mut patterns = Vec::new();
+        for (term_index, term) in terms {
+            matcher.term_indices.push(term_index);
+            matcher.term_lens.push(term.split(' ').count());
+            patterns.push(term);
+        }
+        if !patterns.is_empty() {
+            matcher.automaton =
+                Some(AhoCorasick::new(patterns).expect("search terms are short enough to build"));
+        }
+        matcher
+    }
+
+    /// The first occurrence of each term in a list of words, as the term index and
+    /// the index of the word it starts at, sorted by term index
+    pub(crate) fn first_occurrences(&self, words: &[&str]) -> Vec<(usize, usize)> {
+        let Some(automaton) = &self.automaton else {
+            return Vec::new();
+        };
+        // words are joined the same way candidates are, with single spaces
+        let mut text = String::new();
+        let mut word_starts = Vec::with_capacity(words.len());
+        let mut word_ends = Vec::with_capacity(words.len());
+        for word in words {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            word_starts.push(text.len());
+            text.push_str(word);
+            word_ends.push(text.len());
+        }
+        let mut found = automaton
+            .find_overlapping_iter(&text)
+            .filter_map(|m| {
+                let pattern = m.pattern().as_usize();
+                let position = word_starts.binary_search(&m.start()).ok()?;
+                let last_word = position + self.term_lens[pattern] - 1;
+                (word_ends.get(last_word) == Some(&m.end()))
+                    .then_some((self.term_indices[pattern], position))
+            })
+            .collect::<Vec<(usize, usize)>>();
+        found.sort_unstable();
+        found.dedup_by_key(|(term_index, _)| *term_index);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_occurrences_on_word_boundaries() {
+        let matcher = ExactMatcher::new([
+            (0, "HEROIN"),
+            (1, "COCAINE"),
+            (2, "COCAINE TOXICITY"),
+            (3, "6-MAM"),
+        ]);
+        let words = [
+            "ACUTE", "COCAINE", "TOXICITY", "HEROINE", "6-MAM", "AND", "HEROIN", "COCAINE",
+        ];
+        assert_eq!(
+            matcher.first_occurrences(&words),
+            vec![(0, 6), (1, 1), (2, 1), (3, 4)]
+        );
+        assert!(matcher
+            .first_occurrences(&["HEROINE", "XCOCAINE"])
+            .is_empty());
+        assert!(ExactMatcher::new([]).first_occurrences(&words).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{HashMap, HashSet};
+
+/// The most edits a term can allow and still be indexed,
+/// the number of deletion variants grows quickly with the edits allowed
+pub const MAX_INDEXED_EDITS: usize = 3;
+
+/// A SymSpell style deletion index over search terms
+/// Each term is stored under every string reachable by deleting up to its maximum edits
+/// of characters. Two strings within `k` edits (Levenshtein, OSA or Damerau-Levenshtein)
+/// always share a string reachable by deleting at most `k` characters from each, so looking
+/// up the deletions of a candidate finds every term that could match it.
+/// Lookups can return terms that are further away, matches still need to be compared.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TermIndex {
+    /// deletion variants of the indexed terms to the indices of the terms
+    deletes: HashMap<String, Vec<usize>>,
+    /// the most deletions of any indexed term, candidates are looked up with as many deletions
+    max_deletes: usize,
+    /// terms that could not be indexed, returned for every candidate
+    unindexed: Vec<usize>,
+}
+
+impl TermIndex {
+    /// Add a term to the index with the edits it allows
+    /// Terms allowing more than [MAX_INDEXED_EDITS] edits are returned for every lookup.
+    pub fn insert(&mut self, term: &str, term_index: usize, max_edits: usize) {
+        if max_edits > MAX_INDEXED_EDITS {
+            self.unindexed.push(term_index);
+            return;
+        }
+        for variant in deletes(term, max_edits) {
+            self.deletes.entry(variant).or_default().push(term_index);
+        }
+        self.max_deletes = self.max_deletes.max(max_edits);
+    }
+
+    /// Add a term that is compared to every candidate, for algorithms the index does not apply to
+    pub fn insert_unindexed(&mut self, term_index: usize) {
+        self.unindexed.push(term_index);
+    }
+
+    /// Whether the index has no terms
+    pub fn is_empty(&self) -> bool {
+        self.deletes.is_empty() && self.unindexed.is_empty()
+    }
+
+    /// The indices of the terms that could be within their maximum edits of a candidate, sorted
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::TermIndex;
+    ///
+    /// let mut index = TermIndex::default();
+    /// index.insert("FENTANYL", 0, 2);
+    /// index.insert("HEROIN", 1, 2);
+    /// index.insert("4-ANPP", 2, 0);
+    /// assert_eq!(index.lookup("FENTANIL"), vec![0]);
+    /// assert_eq!(index.lookup("4-ANPP"), vec![2]);
+    /// assert!(index.lookup("4-ANP").is_empty());
+    /// ```
+    pub fn lookup(&self, candidate: &str) -> Vec<usize> {
+        let mut found = self.unindexed.clone();
+        if !self.deletes.is_empty() {
+            let chars = candidate.chars().collect::<Vec<char>>();
+            let mut variant = String::with_capacity(candidate.len());
+            visit_deletes(
+                &chars,
+                self.max_deletes,
+                0,
+                &mut Vec::new(),
+                &mut variant,
+                &mut |v| {
+                    if let Some(terms) = self.deletes.get(v) {
+                        found.extend(terms);
+                    }
+                },
+            );
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+}
+
+/// Call `f` with every string reachable by deleting up to `max_deletes` characters after `start`
+/// Variants are built in a reused buffer, repeated characters can produce the same variant twice.
+fn visit_deletes(
+    chars: &[char],
+    max_deletes: usize,
+    start: usize,
+    deleted: &mut Vec<usize>,
+    variant: &mut String,
+    f: &mut impl FnMut(&str),
+) {
+    variant.clear();
+    variant.extend(
+        chars
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !deleted.contains(i))
+            .map(|(_, c)| c),
+    );
+    f(variant);
+    if deleted.len() == max_deletes {
+        return;
+    }
+    for i in start..chars.len() {
+        deleted.push(i);
+        visit_deletes(chars, max_deletes, i + 1, deleted, variant, f);
+        deleted.pop();
+    }
+}
+
+/// Every string reachable by deleting up to `max_deletes` characters, including the string itself
+fn deletes(s: &str, max_deletes: usize) -> HashSet<String> {
+    let chars = s.chars().collect::<Vec<char>>();
+    let mut variants = HashSet::new();
+    let mut variant = String::with_capacity(s.len());
+    visit_deletes(
+        &chars,
+        max_deletes,
+        0,
+        &mut Vec::new(),
+        &mut variant,
+        &mut |v| {
+            if !variants.contains(v) {
+                variants.insert(v.to_string());
+            }
+        },
+    );
+    variants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{matcher_from_name, DISTANCE_MATCHERS};
+
+    #[test]
+    fn test_deletes() {
+        assert_eq!(deletes("AB", 0), HashSet::from(["AB".to_string()]));
+        assert_eq!(
+            deletes("AAB", 2),
+            HashSet::from(["AAB", "AB", "AA", "A", "B"].map(String::from))
+        );
+    }
+
+    #[test]
+    fn test_lookup_finds_everything_within_edits() {
+        let terms = ["FENTANYL", "HEROIN", "CA", "6-MAM", "COCAINE TOXICITY"];
+        let candidates = [
+            "FENTANIL",
+            "FETNANYL",
+            "FENTNYL",
+            "HERION",
+            "HEROINE",
+            "ABC",
+            "AC",
+            "6-MAN",
+            "COCAINE",
+            "COCAINE TOXICTY",
+            "MORPHINE",
+        ];
+        let mut index = TermIndex::default();
+        for (i, term) in terms.iter().enumerate() {
+            index.insert(term, i, 2);
+        }
+        for name in DISTANCE_MATCHERS {
+            let matcher = matcher_from_name(name).unwrap();
+            for candidate in candidates {
+                let found = index.lookup(candidate);
+                for (i, term) in terms.iter().enumerate() {
+                    if matcher.score(term, candidate) <= 2.0 {
+                        assert!(found.contains(&i), "{} {} {}", name, term, candidate);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unindexed_terms_always_returned() {
+        let mut index = TermIndex::default();
+        assert!(index.is_empty());
+        index.insert("FENTANYL", 0, MAX_INDEXED_EDITS + 1);
+        index.insert_unindexed(1);
+        assert_eq!(index.lookup("HEROIN"), vec![0, 1]);
+    }
+}
@@ -1,15 +1,71 @@
-use color_eyre::{
-    eyre::{eyre, Context, ContextCompat},
-    Result,
-};
-use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
+use indicatif::{ProgressBar, ProgressStyle};
 
-use std::{collections::HashSet, fs::File, path::Path, time::Duration};
+use std::{
+    borrow::Cow,
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, Read},
+    ops::Range,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use itertools::Itertools;
 
+use csv::StringRecord;
+use rayon::prelude::*;
+
+mod cache;
+use cache::SharedCache;
+
+mod error;
+use error::IoContext;
+pub use error::{Error, Result};
+
+mod cleaning;
+pub use cleaning::TextCleaning;
+
+mod compression;
+pub use compression::{
+    compress_writer, decompress_reader, CompressedWriter, Compression, FinishWrite,
+};
+
+#[cfg(feature = "arrow")]
+mod columnar;
+#[cfg(feature = "arrow")]
+pub use columnar::{match_schema, ColumnarMatchWriter};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteMatchWriter;
+
+mod negation;
+pub use negation::{sentence_breaks, Assertion, NegationRules, Trigger, TriggerKind};
+
+mod output;
+pub use output::{
+    initialize_writer, CsvMatchWriter, JsonlMatchWriter, MatchWriter, OutputFormat, OutputShape,
+    RecordOutput, WideMatchWriter, OUTPUT_FIELDS,
+};
+
+mod exact;
+use exact::ExactMatcher;
+
+mod index;
+pub use index::{TermIndex, MAX_INDEXED_EDITS};
+
+mod matcher;
+pub use matcher::{
+    matcher_from_name, DamerauLevenshtein, Jaro, JaroWinkler, Levenshtein, Matcher, MatcherKind,
+    NormalizedLevenshtein, Osa, SorensenDice, DISTANCE_MATCHERS, SIMILARITY_MATCHERS,
+};
+
 /// Create a spinner with default style, takes a message
 fn initialize_spinner_style(msg: String) -> ProgressBar {
     let pb = ProgressBar::new_spinner();
@@ -42,55 +98,407 @@ fn initialize_progress_bar(msg: String, len: u64) -> ProgressBar {
     .with_message(msg)
 }
 
+/// Initialize a progress bar measured in bytes, takes a message and length in bytes
+fn initialize_byte_progress_bar(msg: String, len: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb.with_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.blue} {msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes:>9}/{total_bytes:9} ({eta})").unwrap()
+            .progress_chars("##-"),
+    )
+    .with_message(msg)
+}
+
 /// Struct to hold search term and metadata
+/// The matching overrides are optional columns in the search terms file
+/// and take precedence over the run-wide [MatchConfig] for this term.
 #[derive(Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct SearchTerm {
     /// The search term
     pub term: String,
-    /// Optional metadata to be included in output
+    /// Optional metadata to be included in output, a pipe-delimited list of tags e.g. `drug|opiate`
     pub metadata: Option<String>,
+    /// Optional normalized concept this term is a synonym or spelling of, e.g. "fentanyl" for "fentanil"
+    #[serde(default)]
+    pub canonical: Option<String>,
+    /// Optional maximum number of edits allowed for this term
+    #[serde(default)]
+    pub max_edits: Option<usize>,
+    /// Optional minimum similarity required for this term, regardless of edits
+    #[serde(default)]
+    pub min_similarity: Option<f64>,
+    /// Optionally only report exact matches for this term
+    #[serde(default)]
+    pub exact_only: Option<bool>,
+    /// Optionally compare this term without uppercasing
+    #[serde(default)]
+    pub case_sensitive: Option<bool>,
+}
+
+impl SearchTerm {
+    /// Whether this term should be compared without uppercasing
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive.unwrap_or(false)
+    }
+
+    /// The concept this term is reported under, the canonical name if given else the term itself
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::SearchTerm;
+    ///
+    /// let term = SearchTerm {
+    ///     term: "FENTANIL".to_string(),
+    ///     canonical: Some("FENTANYL".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(term.canonical_name(), "FENTANYL");
+    /// assert_eq!(SearchTerm::default().canonical_name(), "");
+    /// ```
+    pub fn canonical_name(&self) -> &str {
+        self.canonical.as_deref().unwrap_or(&self.term)
+    }
+
+    /// Clean the term and canonical name the same way as the text they are compared to
+    /// Case-sensitive terms only have punctuation stripped. Words are separated by single spaces.
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::{SearchTerm, TextCleaning};
+    ///
+    /// let term = SearchTerm {
+    ///     term: "fentanyl, 4-anpp".to_string(),
+    ///     canonical: Some(" ".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// let term = term.cleaned(TextCleaning::Ascii);
+    /// assert_eq!(term.term, "FENTANYL 4-ANPP");
+    /// assert_eq!(term.canonical, None);
+    /// ```
+    pub fn cleaned(mut self, cleaning: TextCleaning) -> Self {
+        let term = if self.is_case_sensitive() {
+            cleaning.strip_punctuation(&self.term)
+        } else {
+            cleaning.clean(&self.term)
+        };
+        self.term = term.split_ascii_whitespace().join(" ");
+        self.canonical = self
+            .canonical
+            .map(|c| cleaning.clean(&c).split_ascii_whitespace().join(" "))
+            .filter(|c| !c.is_empty());
+        self
+    }
+
+    /// The tags in this term's metadata, from broadest to most specific as written
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.metadata.as_deref().into_iter().flat_map(metadata_tags)
+    }
+
+    /// Whether any of this term's tags is `tag`, ignoring case
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::SearchTerm;
+    ///
+    /// let term = SearchTerm {
+    ///     term: "4-ANPP".to_string(),
+    ///     metadata: Some("drug|fen_analog|fentanyl|opiate".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// assert!(term.has_tag("OPIATE"));
+    /// assert!(!term.has_tag("stimulant"));
+    /// ```
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+}
+
+/// Function to split a pipe-delimited metadata string into its tags
+/// Empty tags are skipped and surrounding whitespace is trimmed.
+/// Example:
+/// ```
+/// use drug_extraction_cli::metadata_tags;
+///
+/// let tags = metadata_tags("drug|fen_analog| fentanyl||opiate").collect::<Vec<_>>();
+/// assert_eq!(tags, vec!["drug", "fen_analog", "fentanyl", "opiate"]);
+/// ```
+pub fn metadata_tags(metadata: &str) -> impl Iterator<Item = &str> {
+    metadata.split('|').map(str::trim).filter(|t| !t.is_empty())
+}
+
+/// Function to select the search terms to use in a run by their metadata tags
+/// A term is kept if it has any of the `include` tags (or `include` is empty)
+/// and none of the `exclude` tags. Tags are compared ignoring case.
+/// Example:
+/// ```
+/// use drug_extraction_cli::{filter_terms_by_tags, SearchTerm};
+///
+/// let term = |term: &str, metadata: &str| SearchTerm {
+///     term: term.to_string(),
+///     metadata: Some(metadata.to_string()),
+///     ..Default::default()
+/// };
+/// let terms = vec![
+///     term("HEROIN", "drug|opiate"),
+///     term("4-ANPP", "drug|fen_analog|fentanyl|opiate"),
+///     term("COCAINE", "drug|stimulant"),
+/// ];
+/// let terms = filter_terms_by_tags(terms, &["opiate".to_string()], &["fen_analog".to_string()]).unwrap();
+/// assert_eq!(terms.len(), 1);
+/// assert_eq!(terms[0].term, "HEROIN");
+/// ```
+pub fn filter_terms_by_tags(
+    terms: Vec<SearchTerm>,
+    include: &[String],
+    exclude: &[String],
+) -> Result<Vec<SearchTerm>> {
+    let terms = terms
+        .into_iter()
+        .filter(|st| include.is_empty() || include.iter().any(|tag| st.has_tag(tag)))
+        .filter(|st| !exclude.iter().any(|tag| st.has_tag(tag)))
+        .collect_vec();
+    if terms.is_empty() {
+        return Err(Error::InvalidOption(
+            "No search terms are left after filtering by tags, check --include-tags and --exclude-tags"
+                .to_string(),
+        ));
+    }
+    Ok(terms)
 }
 
 /// Struct to hold search output
 #[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct SearchOutput<'a> {
     /// The row id of the matched record, either from specified column or line number
-    row_id: &'a str,
+    pub row_id: &'a str,
     /// The search term that was matched
-    search_term: &'a str,
+    pub search_term: &'a str,
     /// The matched term from the record
-    matched_term: &'a str,
+    pub matched_term: &'a str,
     /// The number of edits required to match the search term
-    edits: usize,
+    pub edits: usize,
     /// The similarity score between the search term and the matched term
-    similarity_score: f64,
+    pub similarity_score: f64,
     /// The field that was searched
-    search_field: &'a str,
+    pub search_field: &'a str,
     /// The metadata associated with the search term
-    metadata: &'a Option<String>,
+    pub metadata: &'a Option<String>,
+    /// Character offset of the start of the match in the original field text
+    pub start: usize,
+    /// Character offset one past the end of the match in the original field text
+    pub end: usize,
+    /// The original field text around the match, if a context window was requested
+    pub context: Option<&'a str>,
+    /// Whether the match is negated, e.g. "no evidence of fentanyl"
+    pub negated: bool,
+    /// Whether the match is uncertain, e.g. "possible fentanyl"
+    pub uncertain: bool,
+    /// The canonical concept of the search term, the search term itself if it has none
+    pub canonical: &'a str,
+}
+
+/// Struct to hold the algorithms and thresholds used when comparing a search term to a candidate
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    /// The maximum number of edits allowed for a match
+    pub max_edits: usize,
+    /// The maximum difference in length between the search term and the candidate
+    pub length_window: usize,
+    /// The minimum similarity required for each number of edits,
+    /// starting at one edit. The last cutoff is reused for any higher edit counts.
+    pub similarity_cutoffs: Vec<f64>,
+    /// The algorithm used to count edits, defaults to [Osa]
+    pub distance_matcher: Arc<dyn Matcher>,
+    /// The algorithm used to score similarity, defaults to [JaroWinkler]
+    pub similarity_matcher: Arc<dyn Matcher>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            max_edits: 2,
+            length_window: 2,
+            similarity_cutoffs: vec![0.95, 0.97],
+            distance_matcher: Arc::new(Osa),
+            similarity_matcher: Arc::new(JaroWinkler),
+        }
+    }
+}
+
+impl PartialEq for MatchConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_edits == other.max_edits
+            && self.length_window == other.length_window
+            && self.similarity_cutoffs == other.similarity_cutoffs
+            && self.distance_matcher.name() == other.distance_matcher.name()
+            && self.similarity_matcher.name() == other.similarity_matcher.name()
+    }
+}
+
+impl MatchConfig {
+    /// Checks that the matchers are of the right kind
+    /// and that the cutoffs are present and valid similarity scores
+    pub fn validate(&self) -> Result<()> {
+        if self.distance_matcher.kind() != MatcherKind::EditDistance {
+            return Err(Error::InvalidOption(format!(
+                "{} does not count edits and cannot be used as the distance algorithm",
+                self.distance_matcher.name()
+            )));
+        }
+        if self.similarity_matcher.kind() != MatcherKind::Similarity {
+            return Err(Error::InvalidOption(format!(
+                "{} is not a similarity score and cannot be used as the similarity algorithm",
+                self.similarity_matcher.name()
+            )));
+        }
+        if self.max_edits > 0 && self.similarity_cutoffs.is_empty() {
+            return Err(Error::InvalidOption(
+                "At least one similarity cutoff is required when edits are allowed".to_string(),
+            ));
+        }
+        if let Some(c) = self
+            .similarity_cutoffs
+            .iter()
+            .find(|c| !(0.0..=1.0).contains(*c))
+        {
+            return Err(Error::InvalidOption(format!(
+                "Similarity cutoff {} is not between 0 and 1",
+                c
+            )));
+        }
+        Ok(())
+    }
+
+    /// The minimum similarity required for a given number of edits
+    /// Returns `None` if the number of edits is above `max_edits`
+    pub fn min_similarity(&self, edits: usize) -> Option<f64> {
+        match edits {
+            0 => Some(0.0),
+            e if e > self.max_edits => None,
+            e => self
+                .similarity_cutoffs
+                .get(e - 1)
+                .or(self.similarity_cutoffs.last())
+                .copied(),
+        }
+    }
+
+    /// Apply the overrides from a search term to this config
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::{MatchConfig, SearchTerm};
+    ///
+    /// let term = SearchTerm {
+    ///     term: "4-ANPP".to_string(),
+    ///     exact_only: Some(true),
+    ///     ..Default::default()
+    /// };
+    /// let config = MatchConfig::default().for_term(&term);
+    /// assert_eq!(config.max_edits, 0);
+    /// ```
+    pub fn for_term(&self, term: &SearchTerm) -> MatchConfig {
+        let mut config = self.clone();
+        if let Some(max_edits) = term.max_edits {
+            config.max_edits = max_edits;
+        }
+        if let Some(min_similarity) = term.min_similarity {
+            config.similarity_cutoffs = vec![min_similarity];
+        }
+        if term.exact_only.unwrap_or(false) {
+            config.max_edits = 0;
+        }
+        config
+    }
+
+    /// Compare a search term to a candidate, returning the edits and similarity score if they match
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::MatchConfig;
+    ///
+    /// let config = MatchConfig::default();
+    /// assert_eq!(config.compare("FENTANYL", "FENTANYL"), Some((0, 1.0)));
+    /// assert!(config.compare("FENTANYL", "FENTANIL").is_some());
+    /// assert!(config.compare("FENTANYL", "COCAINE").is_none());
+    /// ```
+    pub fn compare(&self, search_term: &str, comparison_term: &str) -> Option<(usize, f64)> {
+        // outside of window
+        if search_term.len().abs_diff(comparison_term.len()) > self.length_window {
+            return None;
+        }
+        let edits = self.distance_matcher.score(search_term, comparison_term) as usize;
+        let cutoff = self.min_similarity(edits)?;
+        if edits == 0 {
+            return Some((edits, 1.0));
+        }
+        let sim = self.similarity_matcher.score(search_term, comparison_term);
+        if sim >= cutoff {
+            Some((edits, sim))
+        } else {
+            None
+        }
+    }
+}
+
+/// Function to deserialize the rows of a csv file, each paired with the 1-based line it starts on
+/// Errors are built with `error` from the line and a message. Lines are counted by the reader,
+/// so rows with quoted line breaks are still located correctly.
+pub(crate) fn deserialize_lines<T: DeserializeOwned, R: Read>(
+    mut rdr: csv::Reader<R>,
+    error: impl Fn(usize, String) -> Error,
+) -> impl Iterator<Item = Result<(usize, T)>> {
+    let headers = rdr.headers().cloned();
+    rdr.into_records().enumerate().map(move |(i, row)| {
+        let headers = headers.as_ref().map_err(|e| error(1, e.to_string()))?;
+        // the header is line 1, a row without a position is assumed to follow it directly
+        let line = |position: Option<&csv::Position>| position.map_or(i + 2, |p| p.line() as usize);
+        let row = row.map_err(|e| error(line(e.position()), e.to_string()))?;
+        let line = line(row.position());
+        row.deserialize(Some(headers))
+            .map(|value| (line, value))
+            .map_err(|e| error(line, e.to_string()))
+    })
 }
 
 /// Function to read in search terms from a csv file
-/// Performs cleaning of terms, ignoring metadata column
-pub fn read_terms_from_file<P: AsRef<Path>>(p: P) -> Result<Vec<SearchTerm>> {
-    let mut rdr = csv::Reader::from_path(p).wrap_err("Unable to read search terms file")?;
+/// Performs cleaning of terms, ignoring metadata column, see [SearchTerm::cleaned].
+/// A term with nothing left to match once cleaned is an error.
+pub fn read_terms_from_file<P: AsRef<Path>>(
+    p: P,
+    cleaning: TextCleaning,
+) -> Result<Vec<SearchTerm>> {
+    let file = File::open(p).io_context("Unable to read search terms file")?;
+    let rdr = csv::Reader::from_reader(file);
     let mut records: Vec<SearchTerm> = Vec::new();
-    for (i, row) in rdr
-        .deserialize()
-        .enumerate()
-        .progress_with(initialize_spinner_style(
-            "Loading Search Terms...".to_string(),
-        ))
-    {
-        let mut record: SearchTerm =
-            row.wrap_err(format!("Could not load search term from line: {}", i))?;
-        record.term = clean_text(&record.term);
+    for row in deserialize_lines(rdr, |line, message| Error::TermParse { line, message }) {
+        let (line, record): (usize, SearchTerm) = row?;
+        let record = record.cleaned(cleaning);
+        if record.term.is_empty() {
+            return Err(Error::TermParse {
+                line,
+                message: "search term is empty once cleaned".to_string(),
+            });
+        }
         records.push(record);
     }
     records.sort_by_key(|x| x.term.split_ascii_whitespace().count());
     Ok(records)
 }
 
+/// Function to read the exclusion (stop) list, one word or phrase per line
+/// Blank lines and lines starting with `#` are skipped. Entries are cleaned
+/// the same way as the text so they can be compared directly to candidates.
+pub fn read_stop_list_from_file<P: AsRef<Path>>(
+    p: P,
+    cleaning: TextCleaning,
+) -> Result<HashSet<String>> {
+    let text = std::fs::read_to_string(p).io_context("Unable to read exclusion list file")?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| cleaning.clean(line).split_ascii_whitespace().join(" "))
+        .filter(|entry| !entry.is_empty())
+        .collect())
+}
+
 /// Function to remove non-alphanumeric characters from a string
 /// keeps hyphens due to their usage in abbreviations/medical terms.
 /// Also uppercase for standardization.
@@ -104,24 +512,129 @@ pub fn read_terms_from_file<P: AsRef<Path>>(p: P) -> Result<Vec<SearchTerm>> {
 /// assert_eq!(cleaned, "THIS IS A TEST-STRING WITH 1234 AND SOME PUNCTUATION");
 /// ```
 pub fn clean_text(s: &str) -> String {
+    strip_punctuation(s).to_ascii_uppercase()
+}
+
+/// Function to remove non-alphanumeric characters from a string
+/// without changing its case, used for case-sensitive search terms.
+/// Example:
+/// ```
+/// use drug_extraction_cli::strip_punctuation;
+///
+/// let s = "Fentanyl, 4-ANPP!";
+/// assert_eq!(strip_punctuation(s), "Fentanyl  4-ANPP");
+/// ```
+pub fn strip_punctuation(s: &str) -> String {
     s.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', " ")
         .trim()
-        .to_ascii_uppercase()
+        .to_string()
+}
+
+/// Struct to hold a word from a piece of text and its position in the original text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    /// The word as it appears in the tokenized text
+    pub text: &'a str,
+    /// Character offset of the start of the word
+    pub start: usize,
+    /// Character offset one past the end of the word
+    pub end: usize,
+}
+
+/// Function to split a string into the same words as [clean_text] followed by splitting on whitespace,
+/// keeping the character offsets of each word so matches can be traced back to the original text.
+/// Example:
+/// ```
+/// use drug_extraction_cli::tokenize;
+///
+/// let tokens = tokenize("Acute fentanyl, 4-ANPP!");
+/// assert_eq!(tokens.len(), 3);
+/// assert_eq!(tokens[1].text, "fentanyl");
+/// assert_eq!((tokens[1].start, tokens[1].end), (6, 14));
+/// assert_eq!(tokens[2].text, "4-ANPP");
+/// ```
+pub fn tokenize(s: &str) -> Vec<Token<'_>> {
+    tokenize_by(s, |c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Split a string into runs of characters that `is_word` accepts
+fn tokenize_by(s: &str, is_word: impl Fn(char) -> bool) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    // byte and character offsets of the word being read
+    let mut word_start = None;
+    for (char_index, (byte_index, c)) in s.char_indices().enumerate() {
+        match (is_word(c), word_start) {
+            (true, None) => word_start = Some((byte_index, char_index)),
+            (false, Some((byte_start, char_start))) => {
+                tokens.push(Token {
+                    text: &s[byte_start..byte_index],
+                    start: char_start,
+                    end: char_index,
+                });
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some((byte_start, char_start)) = word_start {
+        let text = &s[byte_start..];
+        tokens.push(Token {
+            text,
+            start: char_start,
+            end: char_start + text.chars().count(),
+        });
+    }
+    tokens
+}
+
+/// A unique candidate n-gram from a piece of text
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    text: String,
+    /// index of the first word of the first occurrence of the n-gram
+    position: usize,
+}
+
+/// Collect the unique candidate n-grams of a given size from a list of words
+fn candidate_grams(words: &[&str], term_len: usize) -> Vec<Candidate> {
+    words
+        .windows(term_len)
+        .enumerate()
+        .unique_by(|(_, gram)| *gram)
+        .map(|(position, gram)| Candidate {
+            text: gram.join(" "),
+            position,
+        })
+        .collect_vec()
+}
+
+/// Slice the text around a character span, with up to `window` characters either side
+fn context_snippet(text: &str, start: usize, end: usize, window: usize) -> &str {
+    let byte_offset = |chars: usize| {
+        text.char_indices()
+            .nth(chars)
+            .map_or(text.len(), |(i, _)| i)
+    };
+    &text[byte_offset(start.saturating_sub(window))..byte_offset(end + window)]
 }
 
 /// Struct to hold information about the dataset
 #[derive(Debug)]
-pub struct DataSet {
+pub struct DataSet<R: Read, M: MatchWriter> {
     /// csv reader for the dataset
-    pub reader: csv::Reader<File>,
-    /// rows in the dataset from first scan
-    pub rows: usize,
+    pub reader: csv::Reader<R>,
+    /// rows in the dataset from first scan, `None` when streaming
+    pub rows: Option<usize>,
+    /// size of the dataset in bytes, if known, used for progress when streaming
+    pub input_size: Option<u64>,
     /// indices of the columns to search in the dataset
     pub clean_search_columns: Vec<ColumnInfo>,
     /// index of the column to use as an id
     pub clean_id_column: Option<ColumnInfo>,
-    /// csv writer for the output file
-    pub writer: csv::Writer<File>,
+    /// indices of the columns copied into the output
+    pub clean_keep_columns: Vec<ColumnInfo>,
+    /// writer for the output destination
+    pub writer: M,
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
@@ -131,7 +644,7 @@ pub struct ColumnInfo {
 }
 
 /// Function to get the column index for a given column name
-/// Returns an error if the column name is not found
+/// Returns [Error::MissingColumn], listing the header, if the column name is not found
 /// Typically ran using the header from the csv reader and is called
 /// inside the [collect_column_info] function to do this for each target column.
 /// Example:
@@ -153,7 +666,10 @@ pub fn get_column_info<S: AsRef<str> + PartialEq>(header: &[S], column: &S) -> R
             name: column.as_ref().to_string(),
             index: i,
         }),
-        None => Err(eyre!("Unable to find column {}", column.as_ref())),
+        None => Err(Error::MissingColumn {
+            column: column.as_ref().to_string(),
+            available: header.iter().map(|h| h.as_ref().to_string()).collect(),
+        }),
     }
 }
 
@@ -185,189 +701,913 @@ pub fn collect_column_info<S: AsRef<str> + PartialEq>(
         .collect()
 }
 
+/// Whether a path refers to stdin/stdout rather than a file
+fn is_std_stream(p: &Path) -> bool {
+    p == Path::new("-")
+}
+
+/// Function to open the dataset for reading
+/// A path of `-` reads from stdin, otherwise the file is opened.
+/// Compressed input is detected and decompressed, see [decompress_reader].
+pub fn open_input<P: AsRef<Path>>(data_file: P) -> Result<(Box<dyn Read>, Compression)> {
+    let data_file = data_file.as_ref();
+    if is_std_stream(data_file) {
+        return decompress_reader(io::stdin().lock(), data_file);
+    }
+    let file = File::open(data_file)
+        .io_context(format!("Unable to open data file {}", data_file.display()))?;
+    decompress_reader(file, data_file)
+}
+
+/// Function to open the output destination for writing
+/// A path of `-` writes to stdout, otherwise the file is created.
+/// Files ending in `.gz`, `.zst` or `.bz2` are compressed, the output is only complete once
+/// [FinishWrite::finish] is called, as [MatchWriter::finish] does.
+/// Returns an error if the file already exists and `force` is not set.
+/// Example:
+/// ```
+/// use drug_extraction_cli::open_output;
+///
+/// let writer = open_output("-", false);
+/// assert!(writer.is_ok());
+/// ```
+pub fn open_output<P: AsRef<Path>>(output: P, force: bool) -> Result<Box<dyn FinishWrite + Send>> {
+    let output = output.as_ref();
+    if is_std_stream(output) {
+        return Ok(Box::new(io::stdout()));
+    }
+    if output.exists() && !force {
+        return Err(Error::Io {
+            context: format!(
+                "Output file {} already exists, use --force to overwrite it",
+                output.display()
+            ),
+            source: io::ErrorKind::AlreadyExists.into(),
+        });
+    }
+    let file = File::create(output)
+        .io_context(format!("Unable to create output file {}", output.display()))?;
+    Ok(Box::new(compress_writer(
+        file,
+        Compression::from_extension(output),
+    )?))
+}
+
 /// Function to initialize the dataset
-pub fn initialize_dataset<P: AsRef<Path>>(
-    data_file: P,
+/// The dataset is read in a single pass from `input`, which can be any source such as a file or stdin.
+/// Matches are written to `writer`, which can be any [MatchWriter] over a file, stdout, etc.
+/// Example:
+/// ```
+/// use drug_extraction_cli::{initialize_dataset, CsvMatchWriter};
+///
+/// let data = "ID,TEXT\n1,fentanyl\n";
+/// let writer = CsvMatchWriter::new(Vec::new(), &[]).unwrap();
+/// let dataset = initialize_dataset(data.as_bytes(), &["TEXT".to_string()], None, &[], writer);
+/// assert!(dataset.is_ok());
+/// let dataset = dataset.unwrap();
+/// assert_eq!(dataset.rows, None);
+/// assert_eq!(dataset.clean_search_columns[0].index, 1);
+/// ```
+pub fn initialize_dataset<R: Read, M: MatchWriter>(
+    input: R,
     search_columns: &[String],
     id_column: Option<String>,
-) -> Result<DataSet> {
-    let mut rdr = csv::Reader::from_path(&data_file).wrap_err("Unable to initialize csv reader")?;
+    keep_columns: &[String],
+    writer: M,
+) -> Result<DataSet<R, M>> {
+    initialize_dataset_with(input, search_columns, id_column, keep_columns, || {
+        Ok(writer)
+    })
+}
+
+/// Initialize the dataset, only creating the writer once the header has been read
+/// and every column found, so a bad column never leaves an output file behind
+fn initialize_dataset_with<R: Read, M: MatchWriter>(
+    input: R,
+    search_columns: &[String],
+    id_column: Option<String>,
+    keep_columns: &[String],
+    writer: impl FnOnce() -> Result<M>,
+) -> Result<DataSet<R, M>> {
+    let mut rdr = csv::Reader::from_reader(input);
     let header = rdr
         .headers()
-        .wrap_err("Unable to parse csv headers")?
+        .map_err(|e| Error::HeaderRead(e.to_string()))?
         .iter()
         .map(clean_text)
         .collect_vec();
-    // clean search cols and id col
+    // clean search cols, id col and keep cols
     let clean_search_cols = search_columns.iter().map(|c| clean_text(c)).collect_vec();
     let clean_id_col = id_column.map(|c| clean_text(&c));
-    let column_info = collect_column_info(&header, &clean_search_cols)
-        .wrap_err("Unable to collect column indices")?;
-    let ds = match clean_id_col {
-        Some(c) => DataSet {
-            reader: csv::Reader::from_path(&data_file)
-                .wrap_err("Unable to initialize csv reader")?,
-            rows: rdr.records().count(),
-            clean_search_columns: column_info,
-            clean_id_column: Some(get_column_info(&header, &c)?),
-            writer: csv::Writer::from_path("output.csv")?,
-        },
-        None => DataSet {
-            reader: csv::Reader::from_path(&data_file)
-                .wrap_err("Unable to initialize csv reader")?,
-            rows: rdr.records().count(),
-            clean_search_columns: column_info,
-            clean_id_column: None,
-            writer: csv::Writer::from_path("output.csv")?,
-        },
+    let clean_keep_cols = keep_columns.iter().map(|c| clean_text(c)).collect_vec();
+    let column_info = collect_column_info(&header, &clean_search_cols)?;
+    let keep_column_info = collect_column_info(&header, &clean_keep_cols)?;
+    let id_column_info = match clean_id_col {
+        Some(c) => Some(get_column_info(&header, &c)?),
+        None => None,
     };
-    Ok(ds)
+    Ok(DataSet {
+        reader: rdr,
+        rows: None,
+        input_size: None,
+        clean_search_columns: column_info,
+        clean_id_column: id_column_info,
+        clean_keep_columns: keep_column_info,
+        writer: writer()?,
+    })
 }
 
-/// Primary search function
-pub fn search(mut dataset: DataSet, search_terms: Vec<SearchTerm>) -> Result<()> {
-    let mut total_records_with_matches = 0;
-    let mut total_records = 0;
-    let mut matched_terms: HashSet<&str> = HashSet::new();
+/// Function to count the records in a csv file, used to size the progress bar
+/// This requires a full extra pass over the file, see [SearchOptions::streaming].
+/// Example:
+/// ```
+/// use drug_extraction_cli::count_rows;
+///
+/// let rows = count_rows("../data/search_terms.csv").unwrap();
+/// assert!(rows > 0);
+/// ```
+pub fn count_rows<P: AsRef<Path>>(data_file: P) -> Result<usize> {
+    let (input, _) = open_input(data_file)?;
+    Ok(csv::Reader::from_reader(input).into_records().count())
+}
+
+/// Number of records read into memory and searched together
+const RECORDS_PER_CHUNK: usize = 4096;
+
+/// How to resolve matches in the same field whose words overlap,
+/// e.g. "COCAINE" and "COCAINE TOXICITY" both matching "cocaine toxicity"
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverlapResolution {
+    /// Keep every match
+    #[default]
+    None,
+    /// Keep the match covering the most words, then the best scoring
+    Longest,
+    /// Keep the match with the fewest edits and highest similarity, then the longest
+    BestScore,
+}
+
+impl OverlapResolution {
+    /// Names of the overlap resolution strategies, as used on the command line
+    pub const NAMES: [&'static str; 3] = ["none", "longest", "best-score"];
+}
+
+impl FromStr for OverlapResolution {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(OverlapResolution::None),
+            "longest" => Ok(OverlapResolution::Longest),
+            "best-score" => Ok(OverlapResolution::BestScore),
+            _ => Err(Error::InvalidOption(format!(
+                "Unknown overlap resolution {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Struct to hold the settings that decide what a [Searcher] matches and reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchOptions {
+    /// The algorithms and thresholds used for matching
+    pub match_config: MatchConfig,
+    /// Number of characters either side of a match to include as context, `None` for no context
+    pub context_window: Option<usize>,
+    /// The trigger phrases used to detect negated and uncertain matches, cleaned with `text_cleaning`
+    pub negation: NegationRules,
+    /// Leave negated matches out of the output
+    pub drop_negated: bool,
+    /// How to resolve overlapping matches in the same field
+    pub overlap_resolution: OverlapResolution,
+    /// Cleaned words and phrases that are never reported as a match, see [read_stop_list_from_file]
+    pub stop_list: HashSet<String>,
+    /// How the text and search terms are cleaned before they are compared,
+    /// the stop list must be read with the same cleaning
+    pub text_cleaning: TextCleaning,
+    /// The number of candidate words and phrases whose matches are remembered across records,
+    /// 0 disables the cache
+    pub cache_size: usize,
+    /// Only report exact matches, skipping fuzzy comparison entirely
+    pub exact_only: bool,
+}
 
-    let spinner =
-        initialize_progress_bar("Searching for matches...".to_string(), dataset.rows as u64);
-    for (i, row) in dataset
-        .reader
-        .records()
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            match_config: MatchConfig::default(),
+            context_window: None,
+            negation: NegationRules::default(),
+            drop_negated: false,
+            overlap_resolution: OverlapResolution::default(),
+            stop_list: HashSet::new(),
+            text_cleaning: TextCleaning::default(),
+            cache_size: 100_000,
+            exact_only: false,
+        }
+    }
+}
+
+/// Struct to hold the options for a search run
+/// The matching settings are passed on to the [Searcher], the rest decide which terms are searched
+/// and how the dataset is read and matches are written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchOptions {
+    /// The settings that decide what is matched
+    pub matching: MatchOptions,
+    /// The number of threads to search with, 0 uses all available cores
+    pub threads: usize,
+    /// Read the dataset in a single pass without counting rows first
+    pub streaming: bool,
+    /// The format to write matches in
+    pub output_format: OutputFormat,
+    /// Whether to write one row per match or one row of flags per record
+    pub output_shape: OutputShape,
+    /// Columns of the dataset copied into each output row
+    pub keep_columns: Vec<String>,
+    /// Only search terms with any of these metadata tags, all terms if empty
+    pub include_tags: Vec<String>,
+    /// Never search terms with any of these metadata tags
+    pub exclude_tags: Vec<String>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            matching: MatchOptions::default(),
+            threads: 1,
+            streaming: false,
+            output_format: OutputFormat::default(),
+            output_shape: OutputShape::default(),
+            keep_columns: Vec::new(),
+            include_tags: Vec::new(),
+            exclude_tags: Vec::new(),
+        }
+    }
+}
+
+/// A search term matched in a piece of text, as returned by [Searcher::find]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Match {
+    /// The search term that was matched
+    pub search_term: String,
+    /// The matched term from the text
+    pub matched_term: String,
+    /// The number of edits required to match the search term
+    pub edits: usize,
+    /// The similarity score between the search term and the matched term
+    pub similarity_score: f64,
+    /// The metadata associated with the search term
+    pub metadata: Option<String>,
+    /// The canonical concept of the search term, the search term itself if it has none
+    pub canonical: String,
+    /// Character offset of the start of the match in the text
+    pub start: usize,
+    /// Character offset one past the end of the match in the text
+    pub end: usize,
+    /// The text around the match, if a context window was requested
+    pub context: Option<String>,
+    /// Whether the match is negated, e.g. "no evidence of fentanyl"
+    pub negated: bool,
+    /// Whether the match is uncertain, e.g. "possible fentanyl"
+    pub uncertain: bool,
+}
+
+/// Search terms paired with their match configs, ready to search any number of texts
+/// The searcher only deals in text, reading datasets and writing matches is left to [search].
+/// It can be shared across threads, matches are cached across calls, see [MatchOptions::cache_size].
+#[derive(Debug)]
+pub struct Searcher {
+    terms: Vec<SearchTerm>,
+    configs: Vec<MatchConfig>,
+    /// runs of terms with the same number of words, in term order
+    groups: Vec<TermGroup>,
+    /// exact occurrences of the terms in the uppercased text and of case-sensitive terms
+    exact: ExactMatcher,
+    cased_exact: ExactMatcher,
+    /// the hit recorded for an exact match of each term, if an exact match is a match at all
+    exact_hits: Vec<Option<TermHit>>,
+    has_case_sensitive: bool,
+    options: MatchOptions,
+    /// the terms matched by recently seen candidates, keyed by group index,
+    /// whether the candidate is cased and the candidate
+    cache: SharedCache<(usize, bool, String), Vec<TermHit>>,
+}
+
+/// A search term a candidate matched
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TermHit {
+    term_index: usize,
+    edits: usize,
+    similarity_score: f64,
+}
+
+/// A run of consecutive search terms with the same number of words, indexed for lookup
+#[derive(Debug, Clone, Default)]
+struct TermGroup {
+    /// the number of words in each term
+    term_len: usize,
+    /// the indices of the terms in the group
+    terms: Range<usize>,
+    /// terms compared to the uppercased text
+    index: TermIndex,
+    /// case-sensitive terms, compared to the text as written
+    cased_index: TermIndex,
+}
+
+/// A search term matched in a piece of text
+#[derive(Debug, Clone, PartialEq)]
+struct TextMatch {
+    /// index of the matched search term
+    term_index: usize,
+    matched_term: String,
+    edits: usize,
+    similarity_score: f64,
+    /// character span of the match in the original text
+    start: usize,
+    end: usize,
+    /// span of the match in words
+    words: Range<usize>,
+    context: Option<String>,
+    assertion: Assertion,
+}
+
+impl TextMatch {
+    /// Whether two matches share any words
+    fn overlaps(&self, other: &TextMatch) -> bool {
+        self.words.start < other.words.end && other.words.start < self.words.end
+    }
+}
+
+/// Function to drop matches that overlap a better match, keeping the original order
+fn resolve_overlaps(matches: Vec<TextMatch>, resolution: OverlapResolution) -> Vec<TextMatch> {
+    let by_score = |a: &TextMatch, b: &TextMatch| {
+        a.edits
+            .cmp(&b.edits)
+            .then(b.similarity_score.total_cmp(&a.similarity_score))
+    };
+    let by_length = |a: &TextMatch, b: &TextMatch| b.words.len().cmp(&a.words.len());
+    let mut ranked = matches.iter().enumerate().collect_vec();
+    match resolution {
+        OverlapResolution::None => return matches,
+        OverlapResolution::Longest => {
+            ranked.sort_by(|(_, a), (_, b)| by_length(a, b).then(by_score(a, b)))
+        }
+        OverlapResolution::BestScore => {
+            ranked.sort_by(|(_, a), (_, b)| by_score(a, b).then(by_length(a, b)))
+        }
+    }
+    let mut kept: Vec<(usize, &TextMatch)> = Vec::new();
+    for (i, m) in ranked {
+        if !kept.iter().any(|(_, k)| k.overlaps(m)) {
+            kept.push((i, m));
+        }
+    }
+    let kept = kept.into_iter().map(|(i, _)| i).collect::<HashSet<usize>>();
+    matches
+        .into_iter()
         .enumerate()
-        .progress_with(spinner.clone())
-    {
-        let record = row.wrap_err(format!("Unable to read record from line {}", i))?;
-
-        let id = match &dataset.clean_id_column {
-            Some(c) => record
-                .get(c.index)
-                .wrap_err(format!(
-                    "Unable to read id column {} from line {}",
-                    c.name, i
-                ))?
-                .to_string(),
-            None => i.to_string(),
+        .filter(|(i, _)| kept.contains(i))
+        .map(|(_, m)| m)
+        .collect()
+}
+
+impl Searcher {
+    /// Prepare search terms for searching
+    /// The match config is validated, see [MatchConfig::validate]. Terms are cleaned with the
+    /// options' text cleaning, see [SearchTerm::cleaned], a term with nothing left to match once
+    /// cleaned or with a minimum similarity that is not between 0 and 1 is an error.
+    pub fn new(terms: Vec<SearchTerm>, options: MatchOptions) -> Result<Self> {
+        options.match_config.validate()?;
+        let terms = terms
+            .into_iter()
+            .map(|st| {
+                let invalid = |message: String| Error::InvalidTerm {
+                    term: st.term.clone(),
+                    message,
+                };
+                if let Some(min_similarity) = st.min_similarity {
+                    if !(0.0..=1.0).contains(&min_similarity) {
+                        return Err(invalid(format!(
+                            "minimum similarity {} is not between 0 and 1",
+                            min_similarity
+                        )));
+                    }
+                }
+                let cleaned = st.clone().cleaned(options.text_cleaning);
+                if cleaned.term.is_empty() {
+                    return Err(invalid("nothing is left to match once cleaned".to_string()));
+                }
+                Ok(cleaned)
+            })
+            .collect::<Result<Vec<SearchTerm>>>()?;
+        let configs = terms
+            .iter()
+            .map(|st| options.match_config.for_term(st))
+            .collect_vec();
+        // the deletion index only finds candidates for the built-in edit distances,
+        // terms are compared to every candidate for any other algorithm
+        let indexable = DISTANCE_MATCHERS.contains(&options.match_config.distance_matcher.name());
+        let mut groups: Vec<TermGroup> = Vec::new();
+        for (term_index, (st, config)) in terms.iter().zip(&configs).enumerate() {
+            let term_len = st.term.split_ascii_whitespace().count();
+            if groups.last().is_none_or(|g| g.term_len != term_len) {
+                groups.push(TermGroup {
+                    term_len,
+                    terms: term_index..term_index,
+                    ..Default::default()
+                });
+            }
+            let group = groups.last_mut().expect("a group was just added");
+            group.terms.end = term_index + 1;
+            if options.exact_only {
+                continue;
+            }
+            let index = if st.is_case_sensitive() {
+                &mut group.cased_index
+            } else {
+                &mut group.index
+            };
+            if indexable {
+                index.insert(&st.term, term_index, config.max_edits);
+            } else {
+                index.insert_unindexed(term_index);
+            }
+        }
+        let exact_hits = terms
+            .iter()
+            .zip(&configs)
+            .enumerate()
+            .map(|(term_index, (st, config))| {
+                let (edits, similarity_score) = config.compare(&st.term, &st.term)?;
+                Some(TermHit {
+                    term_index,
+                    edits,
+                    similarity_score,
+                })
+            })
+            .collect_vec();
+        let exact_terms = |cased: bool| {
+            let exact_hits = &exact_hits;
+            terms
+                .iter()
+                .enumerate()
+                .filter(move |(i, st)| st.is_case_sensitive() == cased && exact_hits[*i].is_some())
+                .map(|(i, st)| (i, st.term.as_str()))
         };
+        let exact = ExactMatcher::new(exact_terms(false));
+        let cased_exact = ExactMatcher::new(exact_terms(true));
+        Ok(Searcher {
+            has_case_sensitive: terms.iter().any(|st| st.is_case_sensitive()),
+            cache: SharedCache::new(options.cache_size),
+            terms,
+            configs,
+            groups,
+            exact,
+            cased_exact,
+            exact_hits,
+            options,
+        })
+    }
 
-        let mut found_match = false;
-        for column in &dataset.clean_search_columns {
-            let text = record.get(column.index).wrap_err(format!(
-                "Unable to read column {} from line {}",
-                column.name, i
-            ))?;
-            let cleaned_text = clean_text(text);
-            let grams = cleaned_text.split_ascii_whitespace().collect_vec();
-            for (term_len, term_list) in &search_terms
+    /// The cleaned search terms, in the order they are searched
+    pub fn terms(&self) -> &[SearchTerm] {
+        &self.terms
+    }
+
+    /// The options the searcher was prepared with
+    pub fn options(&self) -> &MatchOptions {
+        &self.options
+    }
+
+    /// Find all search term matches in a piece of text, in term order
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::{MatchOptions, SearchTerm, Searcher};
+    ///
+    /// let terms = vec![SearchTerm {
+    ///     term: "fentanyl".to_string(),
+    ///     metadata: Some("opioid".to_string()),
+    ///     ..Default::default()
+    /// }];
+    /// let searcher = Searcher::new(terms, MatchOptions::default()).unwrap();
+    /// let matches = searcher.find("No evidence of fentanil");
+    /// assert_eq!(matches.len(), 1);
+    /// assert_eq!(matches[0].search_term, "FENTANYL");
+    /// assert_eq!(matches[0].matched_term, "FENTANIL");
+    /// assert_eq!(matches[0].edits, 1);
+    /// assert_eq!((matches[0].start, matches[0].end), (15, 23));
+    /// assert!(matches[0].negated);
+    /// ```
+    pub fn find(&self, text: &str) -> Vec<Match> {
+        self.find_matches(text)
+            .into_iter()
+            .map(|m| {
+                let search_term = &self.terms[m.term_index];
+                Match {
+                    search_term: search_term.term.clone(),
+                    matched_term: m.matched_term,
+                    edits: m.edits,
+                    similarity_score: m.similarity_score,
+                    metadata: search_term.metadata.clone(),
+                    canonical: search_term.canonical_name().to_string(),
+                    start: m.start,
+                    end: m.end,
+                    context: m.context,
+                    negated: m.assertion.negated,
+                    uncertain: m.assertion.uncertain,
+                }
+            })
+            .collect()
+    }
+
+    /// Compare a candidate to the terms of an index that could match it
+    fn compare(&self, index: &TermIndex, candidate: &str) -> Vec<TermHit> {
+        index
+            .lookup(candidate)
+            .into_iter()
+            .filter_map(|term_index| {
+                let (edits, similarity_score) =
+                    self.configs[term_index].compare(&self.terms[term_index].term, candidate)?;
+                Some(TermHit {
+                    term_index,
+                    edits,
+                    similarity_score,
+                })
+            })
+            .collect()
+    }
+
+    /// Find all search term matches in a piece of text, with the word spans used to compare them
+    /// A candidate that occurs more than once is reported at its least negated occurrence.
+    fn find_matches(&self, text: &str) -> Vec<TextMatch> {
+        let cleaning = self.options.text_cleaning;
+        // offsets are mapped back to the original text if normalizing moved any characters
+        let (upper_text, original_offsets) = cleaning.normalize(text, true);
+        let original_offset = |i: usize| original_offsets.as_ref().map_or(i, |o| o[i]);
+        let tokens = cleaning.tokenize(&upper_text);
+        let words = tokens.iter().map(|t| t.text).collect_vec();
+        let cased_text = if self.has_case_sensitive {
+            cleaning.normalize(text, false).0
+        } else {
+            Cow::Borrowed("")
+        };
+        let cased_words = cleaning
+            .tokenize(&cased_text)
+            .iter()
+            .map(|t| t.text)
+            .collect_vec();
+        // exact occurrences are found in one pass, fuzzy comparison only sees the other candidates
+        let exact = self.exact.first_occurrences(&words);
+        let cased_exact = self.cased_exact.first_occurrences(&cased_words);
+        let stop_list = &self.options.stop_list;
+        // only needed once something matches
+        let breaks = OnceCell::new();
+        let mut matches = Vec::new();
+        for (group_index, group) in self.groups.iter().enumerate() {
+            let term_len = group.term_len;
+            // known false positives are never reported, however exact or similar they are
+            let exact_candidates = exact
                 .iter()
-                .group_by(|st| st.term.split_ascii_whitespace().count())
-            {
-                let combos = if term_len == 1 {
-                    term_list.cartesian_product(
-                        grams
-                            .iter()
-                            .unique()
-                            .map(|word| word.to_string())
-                            .collect_vec(),
-                    )
+                .chain(&cased_exact)
+                .filter(|(term_index, _)| group.terms.contains(term_index))
+                .filter_map(|&(term_index, position)| {
+                    let text = &self.terms[term_index].term;
+                    if !stop_list.is_empty() && stop_list.contains(&text.to_uppercase()) {
+                        return None;
+                    }
+                    Some((
+                        self.exact_hits[term_index]?,
+                        Candidate {
+                            text: text.clone(),
+                            position,
+                        },
+                    ))
+                })
+                .collect_vec();
+            let (mut candidates, mut cased_candidates) = if self.options.exact_only {
+                (Vec::new(), Vec::new())
+            } else {
+                (
+                    candidate_grams(&words, term_len),
+                    candidate_grams(&cased_words, term_len),
+                )
+            };
+            let is_exact = |c: &Candidate, cased: bool| {
+                exact_candidates.iter().any(|(hit, e)| {
+                    self.terms[hit.term_index].is_case_sensitive() == cased && e.text == c.text
+                })
+            };
+            candidates.retain(|c| !stop_list.contains(&c.text) && !is_exact(c, false));
+            cased_candidates
+                .retain(|c| !stop_list.contains(&c.text.to_uppercase()) && !is_exact(c, true));
+            // the terms each candidate matched, in term then text order
+            let mut hits = exact_candidates
+                .iter()
+                .map(|(hit, candidate)| (*hit, candidate))
+                .collect_vec();
+            for (cased, index, candidates) in [
+                (false, &group.index, &candidates),
+                (true, &group.cased_index, &cased_candidates),
+            ] {
+                if index.is_empty() {
+                    continue;
+                }
+                for candidate in candidates {
+                    let key = (group_index, cased, candidate.text.clone());
+                    let term_hits = self
+                        .cache
+                        .get_or_insert_with(key, || self.compare(index, &candidate.text));
+                    hits.extend(term_hits.into_iter().map(|hit| (hit, candidate)));
+                }
+            }
+            hits.sort_unstable_by_key(|(hit, candidate)| (hit.term_index, candidate.position));
+            for (hit, candidate) in hits {
+                let TermHit {
+                    term_index,
+                    edits,
+                    similarity_score,
+                } = hit;
+                let candidate_words = if self.terms[term_index].is_case_sensitive() {
+                    &cased_words
                 } else {
-                    term_list.cartesian_product(
-                        grams
-                            .windows(term_len)
-                            .unique()
-                            .map(|words| words.join(" "))
-                            .collect_vec(),
-                    )
+                    &words
                 };
-                for (search_term, comparison_term) in combos {
-                    // outside of window
-                    if search_term.term.len().abs_diff(comparison_term.len()) > 2 {
-                        continue;
-                    }
-                    let edits = strsim::osa_distance(&search_term.term, &comparison_term);
-                    match edits {
-                        0 => {
-                            dataset
-                                .writer
-                                .serialize(SearchOutput {
-                                    row_id: &id,
-                                    search_term: &search_term.term,
-                                    matched_term: &comparison_term,
-                                    edits,
-                                    similarity_score: 1.0,
-                                    search_field: &column.name,
-                                    metadata: &search_term.metadata,
-                                })
-                                .wrap_err("Enable to serialize output")?;
-                            found_match = true;
-                            matched_terms.insert(&search_term.term);
-                        }
-                        1 => {
-                            let sim = strsim::jaro_winkler(&search_term.term, &comparison_term);
-                            if sim >= 0.95 {
-                                dataset
-                                    .writer
-                                    .serialize(SearchOutput {
-                                        row_id: &id,
-                                        search_term: &search_term.term,
-                                        matched_term: &comparison_term,
-                                        edits,
-                                        similarity_score: sim,
-                                        search_field: &column.name,
-                                        metadata: &search_term.metadata,
-                                    })
-                                    .wrap_err("Enable to serialize output")?;
-                                found_match = true;
-                                matched_terms.insert(&search_term.term);
-                            }
-                        }
-                        2 => {
-                            let sim = strsim::jaro_winkler(&search_term.term, &comparison_term);
-                            if sim >= 0.97 {
-                                dataset
-                                    .writer
-                                    .serialize(SearchOutput {
-                                        row_id: &id,
-                                        search_term: &search_term.term,
-                                        matched_term: &comparison_term,
-                                        edits,
-                                        similarity_score: sim,
-                                        search_field: &column.name,
-                                        metadata: &search_term.metadata,
-                                    })
-                                    .wrap_err("Enable to serialize output")?;
-                                found_match = true;
-                                matched_terms.insert(&search_term.term);
-                            }
-                        }
-                        _ => continue,
-                    }
+                let gram = &candidate_words[candidate.position..candidate.position + term_len];
+                let breaks = breaks.get_or_init(|| sentence_breaks(&upper_text, &tokens));
+                let Some((position, assertion)) = candidate_words
+                    .windows(term_len)
+                    .enumerate()
+                    .skip(candidate.position)
+                    .filter(|(_, w)| *w == gram)
+                    .map(|(p, _)| {
+                        let assertion =
+                            self.options
+                                .negation
+                                .assess(&tokens, breaks, p, p + term_len);
+                        (p, assertion)
+                    })
+                    .min_by_key(|(_, assertion)| *assertion)
+                else {
+                    continue;
+                };
+                if assertion.negated && self.options.drop_negated {
+                    continue;
+                }
+                let start = original_offset(tokens[position].start);
+                let end = original_offset(tokens[position + term_len - 1].end - 1) + 1;
+                matches.push(TextMatch {
+                    term_index,
+                    matched_term: candidate.text.clone(),
+                    edits,
+                    similarity_score,
+                    start,
+                    end,
+                    words: position..position + term_len,
+                    context: self
+                        .options
+                        .context_window
+                        .map(|window| context_snippet(text, start, end, window).to_string()),
+                    assertion,
+                });
+            }
+        }
+        resolve_overlaps(matches, self.options.overlap_resolution)
+    }
+}
+
+/// The id, kept column values and matches found in a single record
+#[derive(Debug, Clone, PartialEq)]
+struct RecordMatches {
+    id: String,
+    kept_columns: Vec<String>,
+    /// matches paired with the index of the search column they were found in
+    matches: Vec<(usize, TextMatch)>,
+}
+
+/// Function to find the id, kept column values and all matches in the search columns of a single record
+fn find_record_matches(
+    record: &StringRecord,
+    row: usize,
+    id_column: &Option<ColumnInfo>,
+    search_columns: &[ColumnInfo],
+    keep_columns: &[ColumnInfo],
+    searcher: &Searcher,
+) -> Result<RecordMatches> {
+    let read_column = |c: &ColumnInfo| {
+        record.get(c.index).ok_or_else(|| Error::RecordRead {
+            row,
+            message: format!("unable to read column {}", c.name),
+        })
+    };
+    let id = match id_column {
+        Some(c) => read_column(c)?.to_string(),
+        None => row.to_string(),
+    };
+    let kept_columns = keep_columns
+        .iter()
+        .map(|c| read_column(c).map(|v| v.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut matches = Vec::new();
+    for (column_index, column) in search_columns.iter().enumerate() {
+        let text = read_column(column)?;
+        matches.extend(
+            searcher
+                .find_matches(text)
+                .into_iter()
+                .map(|m| (column_index, m)),
+        );
+    }
+    Ok(RecordMatches {
+        id,
+        kept_columns,
+        matches,
+    })
+}
+
+/// Initialize the search progress bar from whatever size information the dataset has
+fn initialize_dataset_progress<R: Read, M: MatchWriter>(dataset: &DataSet<R, M>) -> ProgressBar {
+    let msg = "Searching for matches...".to_string();
+    match (dataset.rows, dataset.input_size) {
+        (Some(rows), _) => initialize_progress_bar(msg, rows as u64),
+        (None, Some(size)) => initialize_byte_progress_bar(msg, size),
+        (None, None) => initialize_spinner_style(msg),
+    }
+}
+
+/// Totals of a search run, returned by [search] for the caller to report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchSummary {
+    /// The number of records searched
+    pub records: usize,
+    /// The number of records with at least one match
+    pub records_with_matches: usize,
+    /// The number of search terms searched for
+    pub search_terms: usize,
+    /// The number of search terms matched at least once
+    pub matched_terms: usize,
+    /// The number of distinct canonical concepts, 0 if no search term has a canonical name
+    pub concepts: usize,
+    /// The concepts matched, with their number of matches and of records with matches,
+    /// most matched first
+    pub concept_counts: Vec<(String, usize, usize)>,
+    /// The uppercased metadata tags matched, with their number of records with matches,
+    /// most records first
+    pub tag_counts: Vec<(String, usize)>,
+    /// Hits and misses of the match cache, `None` if it was disabled
+    pub cache_stats: Option<(usize, usize)>,
+}
+
+/// Primary search function
+/// Records are searched in chunks across `threads` threads, 0 uses all available cores,
+/// and written in their original order.
+/// Progress is drawn on stderr so the output can be piped, the totals are returned to be reported.
+pub fn search<R: Read, M: MatchWriter>(
+    mut dataset: DataSet<R, M>,
+    searcher: &Searcher,
+    threads: usize,
+) -> Result<SearchSummary> {
+    let mut total_records_with_matches = 0;
+    let mut total_records = 0;
+    let mut matched_terms: HashSet<&str> = HashSet::new();
+    // matches and records per canonical concept
+    let mut concept_counts: HashMap<&str, (usize, usize)> = HashMap::new();
+    // records with any match per metadata tag, keyed by the uppercased tag
+    let mut tag_counts: HashMap<String, usize> = HashMap::new();
+    let search_terms = searcher.terms();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+
+    let spinner = initialize_dataset_progress(&dataset);
+    let by_bytes = dataset.rows.is_none();
+    let records = dataset.reader.records().enumerate();
+    for chunk in &records.chunks(RECORDS_PER_CHUNK) {
+        let chunk = chunk
+            .map(|(i, row)| {
+                Ok((
+                    i,
+                    row.map_err(|e| Error::RecordRead {
+                        row: i,
+                        message: e.to_string(),
+                    })?,
+                ))
+            })
+            .collect::<Result<Vec<(usize, StringRecord)>>>()?;
+        match chunk.last() {
+            Some((_, record)) if by_bytes => {
+                if let Some(position) = record.position() {
+                    spinner.set_position(position.byte());
                 }
             }
+            Some((i, _)) => spinner.set_position(*i as u64 + 1),
+            None => {}
         }
-        if found_match {
-            total_records_with_matches += 1;
+        let results = pool.install(|| {
+            chunk
+                .par_iter()
+                .map(|(i, record)| {
+                    find_record_matches(
+                        record,
+                        *i,
+                        &dataset.clean_id_column,
+                        &dataset.clean_search_columns,
+                        &dataset.clean_keep_columns,
+                        searcher,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        for RecordMatches {
+            id,
+            kept_columns,
+            matches,
+        } in results
+        {
+            let outputs = matches
+                .iter()
+                .map(|(column_index, m)| {
+                    let search_term = &search_terms[m.term_index];
+                    SearchOutput {
+                        row_id: &id,
+                        search_term: &search_term.term,
+                        matched_term: &m.matched_term,
+                        edits: m.edits,
+                        similarity_score: m.similarity_score,
+                        search_field: &dataset.clean_search_columns[*column_index].name,
+                        metadata: &search_term.metadata,
+                        start: m.start,
+                        end: m.end,
+                        context: m.context.as_deref(),
+                        negated: m.assertion.negated,
+                        uncertain: m.assertion.uncertain,
+                        canonical: search_term.canonical_name(),
+                    }
+                })
+                .collect_vec();
+            let record = RecordOutput {
+                row_id: &id,
+                kept_columns: &kept_columns,
+            };
+            dataset.writer.write_record(&record, &outputs)?;
+            for m in &matches {
+                matched_terms.insert(&search_terms[m.1.term_index].term);
+            }
+            let concepts = matches
+                .iter()
+                .counts_by(|m| search_terms[m.1.term_index].canonical_name());
+            for (concept, count) in concepts {
+                let totals = concept_counts.entry(concept).or_default();
+                totals.0 += count;
+                totals.1 += 1;
+            }
+            let tags = matches
+                .iter()
+                .flat_map(|m| search_terms[m.1.term_index].tags())
+                .map(|tag| tag.to_ascii_uppercase())
+                .collect::<HashSet<String>>();
+            for tag in tags {
+                *tag_counts.entry(tag).or_default() += 1;
+            }
+            if !matches.is_empty() {
+                total_records_with_matches += 1;
+            }
+            total_records += 1;
         }
-        total_records += 1;
     }
-    dataset.writer.flush().wrap_err("Unable to flush writer")?;
+    dataset.writer.finish()?;
     spinner.finish_with_message("Done!");
 
-    println!(
-        "Found matches in {:} of {:} records ({:.2}%)",
-        total_records_with_matches,
-        total_records,
-        (total_records_with_matches as f64 / total_records as f64) * 100.0
-    );
-    println!(
-        "Found {:} of {:} search terms ({:.2}%)",
-        matched_terms.len(),
-        search_terms.len(),
-        (matched_terms.len() as f64 / search_terms.len() as f64) * 100.0
-    );
-
-    Ok(())
+    let concepts = if search_terms.iter().any(|st| st.canonical.is_some()) {
+        search_terms
+            .iter()
+            .map(|st| st.canonical_name())
+            .collect::<HashSet<&str>>()
+            .len()
+    } else {
+        0
+    };
+    Ok(SearchSummary {
+        records: total_records,
+        records_with_matches: total_records_with_matches,
+        search_terms: search_terms.len(),
+        matched_terms: matched_terms.len(),
+        concepts,
+        concept_counts: concept_counts
+            .into_iter()
+            .map(|(concept, (match_count, record_count))| {
+                (concept.to_string(), match_count, record_count)
+            })
+            .sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)))
+            .collect(),
+        tag_counts: tag_counts
+            .into_iter()
+            .sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)))
+            .collect(),
+        cache_stats: searcher.cache.is_enabled().then(|| searcher.cache.stats()),
+    })
 }
 
 pub fn run_searcher<P: AsRef<Path>>(
@@ -375,15 +1615,87 @@ pub fn run_searcher<P: AsRef<Path>>(
     search_terms_file: P,
     search_columns: Vec<String>,
     id_column: Option<String>,
-) -> Result<()> {
-    let search_terms = read_terms_from_file(search_terms_file)?;
-    let dataset = initialize_dataset(data_file, &search_columns, id_column)?;
-    search(dataset, search_terms)
+    output: P,
+    force: bool,
+    options: SearchOptions,
+) -> Result<SearchSummary> {
+    let spinner = initialize_spinner_style("Loading Search Terms...".to_string());
+    let search_terms = read_terms_from_file(&search_terms_file, options.matching.text_cleaning)?;
+    spinner.finish();
+    let search_terms =
+        filter_terms_by_tags(search_terms, &options.include_tags, &options.exclude_tags)?;
+    let kept_columns = options
+        .keep_columns
+        .iter()
+        .map(|c| clean_text(c))
+        .collect_vec();
+    #[cfg(feature = "sqlite")]
+    if options.output_format == OutputFormat::Sqlite && is_std_stream(output.as_ref()) {
+        return Err(Error::InvalidOption(
+            "The sqlite output format can not be written to stdout".to_string(),
+        ));
+    }
+    if options.output_shape == OutputShape::Wide && options.output_format != OutputFormat::Csv {
+        return Err(Error::InvalidOption(
+            "The wide output shape can only be written as csv".to_string(),
+        ));
+    }
+    let searcher = Searcher::new(search_terms, options.matching.clone())?;
+    let (input, compression) = open_input(&data_file)?;
+    // the output is only created once the input and its columns are known to be good
+    let writer = || -> Result<Box<dyn MatchWriter>> {
+        Ok(match options.output_shape {
+            // the database is appended to rather than overwritten so skips the output file checks
+            #[cfg(feature = "sqlite")]
+            OutputShape::Long if options.output_format == OutputFormat::Sqlite => {
+                Box::new(SqliteMatchWriter::open(
+                    output,
+                    &search_terms_file.as_ref().display().to_string(),
+                    &data_file.as_ref().display().to_string(),
+                    &options.matching.match_config,
+                    &kept_columns,
+                )?)
+            }
+            OutputShape::Long => initialize_writer(
+                open_output(output, force)?,
+                options.output_format,
+                &kept_columns,
+            )?,
+            OutputShape::Wide => {
+                let search_fields = search_columns.iter().map(|c| clean_text(c)).collect_vec();
+                Box::new(WideMatchWriter::new(
+                    open_output(output, force)?,
+                    searcher.terms(),
+                    &search_fields,
+                    &kept_columns,
+                )?)
+            }
+        })
+    };
+    let mut dataset = initialize_dataset_with(
+        input,
+        &search_columns,
+        id_column,
+        &options.keep_columns,
+        writer,
+    )?;
+    // stdin can only be read once so is always streamed
+    if !is_std_stream(data_file.as_ref()) {
+        // byte progress is measured on decompressed data so only works for plain files
+        if compression == Compression::None {
+            dataset.input_size = std::fs::metadata(&data_file).ok().map(|m| m.len());
+        }
+        if !options.streaming {
+            dataset.rows = Some(count_rows(&data_file)?);
+        }
+    }
+    search(dataset, &searcher, options.threads)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use color_eyre::Result;
 
     #[test]
     fn test_clean_text_no_changes() {
@@ -463,7 +1775,13 @@ mod tests {
     fn test_get_column_info_errors() {
         let header = vec!["a", "b", "c"];
         let col = "d";
-        assert!(get_column_info(&header, &col).is_err());
+        match get_column_info(&header, &col) {
+            Err(Error::MissingColumn { column, available }) => {
+                assert_eq!(column, "d");
+                assert_eq!(available, vec!["a", "b", "c"]);
+            }
+            other => panic!("expected a missing column error, got {:?}", other),
+        }
     }
 
     #[test]
@@ -505,6 +1823,803 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_open_output_refuses_overwrite() {
+        let path = std::env::temp_dir().join("drug_extraction_open_output_test.csv");
+        File::create(&path).unwrap();
+        assert!(open_output(&path, false).is_err());
+        assert!(open_output(&path, true).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_match_config_default_thresholds() {
+        let config = MatchConfig::default();
+        assert_eq!(config.min_similarity(0), Some(0.0));
+        assert_eq!(config.min_similarity(1), Some(0.95));
+        assert_eq!(config.min_similarity(2), Some(0.97));
+        assert_eq!(config.min_similarity(3), None);
+    }
+
+    #[test]
+    fn test_match_config_reuses_last_cutoff() {
+        let config = MatchConfig {
+            max_edits: 3,
+            length_window: 3,
+            similarity_cutoffs: vec![0.9],
+            ..Default::default()
+        };
+        assert_eq!(config.min_similarity(3), Some(0.9));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_match_config_length_window() {
+        let config = MatchConfig {
+            length_window: 0,
+            ..Default::default()
+        };
+        assert!(config.compare("FENTANYL", "FENTANYLS").is_none());
+        assert!(config.compare("FENTANYL", "FENTANIL").is_some());
+    }
+
+    #[test]
+    fn test_match_config_validate_errors() {
+        let config = MatchConfig {
+            similarity_cutoffs: vec![1.5],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = MatchConfig {
+            similarity_cutoffs: vec![],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_match_config_for_term_overrides() {
+        let base = MatchConfig::default();
+        let term = SearchTerm {
+            term: "ACETYL".to_string(),
+            max_edits: Some(1),
+            min_similarity: Some(0.99),
+            ..Default::default()
+        };
+        let config = base.for_term(&term);
+        assert_eq!(config.max_edits, 1);
+        assert_eq!(config.similarity_cutoffs, vec![0.99]);
+        assert!(config.compare("ACETYL", "ACETYLS").is_none());
+        assert_eq!(base.for_term(&SearchTerm::default()), base);
+    }
+
+    #[test]
+    fn test_read_terms_with_overrides() -> Result<()> {
+        let path = std::env::temp_dir().join("drug_extraction_term_overrides_test.csv");
+        std::fs::write(
+            &path,
+            "term,metadata,canonical,max_edits,min_similarity,exact_only,case_sensitive\n\
+             fentanyl,drug,,,,,\n\
+             4-ANPP,drug,4-anpp (despropionyl fentanyl),,,true,true\n",
+        )?;
+        let terms = read_terms_from_file(&path, TextCleaning::Ascii)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(terms[0].term, "FENTANYL");
+        assert_eq!(terms[0].exact_only, None);
+        assert_eq!(terms[0].canonical_name(), "FENTANYL");
+        assert_eq!(terms[1].term, "4-ANPP");
+        assert_eq!(terms[1].exact_only, Some(true));
+        assert!(terms[1].is_case_sensitive());
+        assert_eq!(terms[1].canonical_name(), "4-ANPP DESPROPIONYL FENTANYL");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_terms_parse_errors() -> Result<()> {
+        let path = std::env::temp_dir().join("drug_extraction_term_errors_test.csv");
+        for (contents, bad_line) in [
+            (
+                "term,metadata,max_edits
+fentanyl,drug,
+heroin,drug,two
+",
+                3,
+            ),
+            (
+                "term
+fentanyl
+...
+",
+                3,
+            ),
+            (
+                "term,metadata
+\"acute
+fentanyl\",drug
+heroin,drug,extra
+",
+                4,
+            ),
+        ] {
+            std::fs::write(&path, contents)?;
+            let result = read_terms_from_file(&path, TextCleaning::Ascii);
+            assert!(
+                matches!(result, Err(Error::TermParse { line, .. }) if line == bad_line),
+                "{:?}",
+                result
+            );
+        }
+        std::fs::remove_file(&path)?;
+        assert!(matches!(
+            read_terms_from_file(&path, TextCleaning::Ascii),
+            Err(Error::Io { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_terms_by_tags() -> Result<()> {
+        let terms = read_terms_from_file("../data/search_terms.csv", TextCleaning::Ascii)?;
+        let total = terms.len();
+        let no_analogs = filter_terms_by_tags(terms.clone(), &[], &["FEN_ANALOG".to_string()])?;
+        assert!(no_analogs.len() < total);
+        assert!(no_analogs.iter().all(|st| !st.has_tag("fen_analog")));
+        assert_eq!(filter_terms_by_tags(terms.clone(), &[], &[])?.len(), total);
+        assert!(filter_terms_by_tags(terms, &["not-a-tag".to_string()], &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_config_matchers() {
+        let config = MatchConfig {
+            distance_matcher: matcher_from_name("levenshtein").unwrap(),
+            similarity_matcher: matcher_from_name("sorensen-dice").unwrap(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_ne!(config, MatchConfig::default());
+        let swapped = MatchConfig {
+            distance_matcher: Arc::new(Jaro),
+            ..Default::default()
+        };
+        assert!(swapped.validate().is_err());
+    }
+
+    #[test]
+    fn test_searcher_find() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "COCAINE".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "FENTANYL".to_string(),
+                ..Default::default()
+            },
+        ];
+        let options = MatchOptions::default();
+        let searcher = Searcher::new(terms, options)?;
+        let matches = searcher.find_matches("Acute fentanil and cocaine toxicity");
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].term_index, 0);
+        assert_eq!(matches[0].edits, 0);
+        assert_eq!(matches[1].term_index, 1);
+        assert_eq!(matches[1].matched_term, "FENTANIL");
+        Ok(())
+    }
+
+    /// Osa under another name, so terms are compared to every candidate instead of indexed
+    #[derive(Debug)]
+    struct UnindexedOsa;
+
+    impl Matcher for UnindexedOsa {
+        fn name(&self) -> &'static str {
+            "unindexed-osa"
+        }
+        fn kind(&self) -> MatcherKind {
+            MatcherKind::EditDistance
+        }
+        fn score(&self, a: &str, b: &str) -> f64 {
+            Osa.score(a, b)
+        }
+    }
+
+    #[test]
+    fn test_searcher_owned_matches() -> Result<()> {
+        let terms = vec![SearchTerm {
+            term: "HEROIN".to_string(),
+            metadata: Some("opioid".to_string()),
+            canonical: Some("DIACETYLMORPHINE".to_string()),
+            ..Default::default()
+        }];
+        let options = MatchOptions {
+            context_window: Some(4),
+            ..Default::default()
+        };
+        let searcher = Searcher::new(terms, options)?;
+        assert_eq!(searcher.terms().len(), 1);
+        let matches =
+            std::thread::scope(|s| s.spawn(|| searcher.find("Possible heroin use")).join())
+                .expect("search thread panicked");
+        assert_eq!(
+            matches,
+            vec![Match {
+                search_term: "HEROIN".to_string(),
+                matched_term: "HEROIN".to_string(),
+                edits: 0,
+                similarity_score: 1.0,
+                metadata: Some("opioid".to_string()),
+                canonical: "DIACETYLMORPHINE".to_string(),
+                start: 9,
+                end: 15,
+                context: Some("ble heroin use".to_string()),
+                negated: false,
+                uncertain: true,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_cleans_terms() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "fentanyl".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "Xylazine,".to_string(),
+                case_sensitive: Some(true),
+                ..Default::default()
+            },
+        ];
+        let searcher = Searcher::new(terms, MatchOptions::default())?;
+        assert_eq!(searcher.terms()[0].term, "FENTANYL");
+        assert_eq!(searcher.terms()[1].term, "Xylazine");
+        assert_eq!(searcher.find("Fentanyl and Xylazine").len(), 2);
+
+        let empty = vec![SearchTerm {
+            term: " ?! ".to_string(),
+            ..Default::default()
+        }];
+        assert!(matches!(
+            Searcher::new(empty, MatchOptions::default()),
+            Err(Error::InvalidTerm { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_rejects_invalid_options() {
+        let terms = vec![SearchTerm {
+            term: "FENTANYL".to_string(),
+            ..Default::default()
+        }];
+        // a similarity score truncates to 0 edits, which would make every word an exact match
+        let options = MatchOptions {
+            match_config: MatchConfig {
+                distance_matcher: Arc::new(Jaro),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            Searcher::new(terms.clone(), options),
+            Err(Error::InvalidOption(_))
+        ));
+        let options = MatchOptions {
+            match_config: MatchConfig {
+                similarity_cutoffs: vec![],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            Searcher::new(terms.clone(), options),
+            Err(Error::InvalidOption(_))
+        ));
+        let terms = vec![SearchTerm {
+            min_similarity: Some(7.0),
+            ..terms[0].clone()
+        }];
+        assert!(matches!(
+            Searcher::new(terms, MatchOptions::default()),
+            Err(Error::InvalidTerm { .. })
+        ));
+    }
+
+    #[test]
+    fn test_searcher_index_matches_scan() -> Result<()> {
+        let mut terms = read_terms_from_file("../data/search_terms.csv", TextCleaning::Ascii)?;
+        terms.push(SearchTerm {
+            term: "Xylazine".to_string(),
+            case_sensitive: Some(true),
+            ..Default::default()
+        });
+        terms.push(SearchTerm {
+            term: "OXYCODONE".to_string(),
+            max_edits: Some(MAX_INDEXED_EDITS + 1),
+            min_similarity: Some(0.8),
+            ..Default::default()
+        });
+        let text = "Acute fentanil, 4-ANP and methamphetamine toxicity with xylazine \
+                    and Xylazene; oxycontin, heroin and 6-acetyl morphine present";
+        let options = MatchOptions {
+            match_config: MatchConfig {
+                length_window: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let indexed = Searcher::new(terms.clone(), options.clone())?.find_matches(text);
+        let options = MatchOptions {
+            match_config: MatchConfig {
+                distance_matcher: Arc::new(UnindexedOsa),
+                ..options.match_config.clone()
+            },
+            ..options
+        };
+        let scanned = Searcher::new(terms, options)?.find_matches(text);
+        assert!(indexed.len() > 4);
+        assert_eq!(indexed, scanned);
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_cache() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "FENTANYL".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "Xylazine".to_string(),
+                case_sensitive: Some(true),
+                ..Default::default()
+            },
+        ];
+        let texts = [
+            "Acute fentanil toxicity",
+            "XYLAZINE and fentanil",
+            "Xylazine",
+        ];
+        let options = MatchOptions::default();
+        let cached = Searcher::new(terms.clone(), options)?;
+        let uncached_options = MatchOptions {
+            cache_size: 0,
+            ..Default::default()
+        };
+        let uncached = Searcher::new(terms, uncached_options)?;
+        for text in texts {
+            assert_eq!(cached.find_matches(text), uncached.find_matches(text));
+        }
+        // words seen in an earlier record are hits, cased and uppercased words are cached apart,
+        // exact matches are never looked up
+        assert_eq!(cached.cache.stats(), (3, 10));
+        assert_eq!(cached.find_matches("Xylazine")[0].term_index, 1);
+        assert!(!uncached.cache.is_enabled());
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_exact_fast_path() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "HEROIN".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "HEROINE".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "COCAINE TOXICITY".to_string(),
+                ..Default::default()
+            },
+        ];
+        let options = MatchOptions::default();
+        let searcher = Searcher::new(terms.clone(), options)?;
+        // an exact match is not also compared to the other terms
+        let matches = searcher.find_matches("Heroin and cocaine, toxicity. Herion");
+        let found = matches
+            .iter()
+            .map(|m| (m.term_index, m.matched_term.as_str(), m.edits))
+            .collect_vec();
+        assert_eq!(
+            found,
+            vec![
+                (0, "HEROIN", 0),
+                (0, "HERION", 1),
+                (2, "COCAINE TOXICITY", 0)
+            ]
+        );
+        assert_eq!((matches[2].start, matches[2].end), (11, 28));
+
+        let exact_options = MatchOptions {
+            exact_only: true,
+            ..Default::default()
+        };
+        let exact = Searcher::new(terms, exact_options)?;
+        let matches = exact.find_matches("Heroin and cocaine, toxicity. Herion");
+        assert_eq!(
+            matches.iter().map(|m| m.term_index).collect_vec(),
+            vec![0, 2]
+        );
+        assert!(exact
+            .find_matches("Heroine")
+            .iter()
+            .all(|m| m.term_index == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_find_spans() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "HEROIN".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "6-ACETYL MORPHINE".to_string(),
+                ..Default::default()
+            },
+        ];
+        let options = MatchOptions {
+            context_window: Some(4),
+            ..Default::default()
+        };
+        let searcher = Searcher::new(terms, options)?;
+        let text = "Café: heroïn; heroin and 6-acetyl  morphine";
+        let matches = searcher.find_matches(text);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].matched_term, "HEROIN");
+        assert_eq!((matches[0].start, matches[0].end), (14, 20));
+        assert_eq!(matches[0].context.as_deref(), Some("ïn; heroin and"));
+        assert_eq!(matches[1].matched_term, "6-ACETYL MORPHINE");
+        let span = text
+            .chars()
+            .skip(matches[1].start)
+            .take(matches[1].end - matches[1].start)
+            .collect::<String>();
+        assert_eq!(span, "6-acetyl  morphine");
+        assert_eq!(
+            matches[1].context.as_deref(),
+            Some("and 6-acetyl  morphine")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_find_negated() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "COCAINE".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "FENTANYL".to_string(),
+                ..Default::default()
+            },
+        ];
+        let text = "No cocaine found. No fentanyl; fentanyl toxicity";
+        let options = MatchOptions::default();
+        let matches = Searcher::new(terms.clone(), options)?.find_matches(text);
+        assert_eq!(matches.len(), 2);
+        assert!(matches[0].assertion.negated);
+        // the affirmed occurrence of fentanyl is reported
+        assert!(!matches[1].assertion.negated);
+        assert_eq!(matches[1].start, 31);
+
+        let options = MatchOptions {
+            drop_negated: true,
+            ..Default::default()
+        };
+        let matches = Searcher::new(terms, options)?.find_matches(text);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].term_index, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_overlaps() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "COCAINE".to_string(),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "COCAINE TOXICITY".to_string(),
+                ..Default::default()
+            },
+        ];
+        let text = "Acute cocaine toxicty";
+        let find = |overlap_resolution| -> Result<Vec<(String, usize)>> {
+            let options = MatchOptions {
+                overlap_resolution,
+                ..Default::default()
+            };
+            Ok(Searcher::new(terms.clone(), options)?
+                .find_matches(text)
+                .into_iter()
+                .map(|m| (m.matched_term, m.start))
+                .collect_vec())
+        };
+        assert_eq!(find(OverlapResolution::None)?.len(), 2);
+        assert_eq!(
+            find(OverlapResolution::Longest)?,
+            vec![("COCAINE TOXICTY".to_string(), 6)]
+        );
+        assert_eq!(
+            find(OverlapResolution::BestScore)?,
+            vec![("COCAINE".to_string(), 6)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_searcher_find_unicode() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: TextCleaning::Unicode.clean("cocaína"),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "6-MAM".to_string(),
+                ..Default::default()
+            },
+        ];
+        let text = "ﬁbrosis; intoxicación por COCAÍNA y 6–MAM";
+        let find = |text_cleaning| -> Result<Vec<TextMatch>> {
+            let options = MatchOptions {
+                text_cleaning,
+                ..Default::default()
+            };
+            Ok(Searcher::new(terms.clone(), options)?.find_matches(text))
+        };
+        assert!(find(TextCleaning::Ascii)?.is_empty());
+        let matches = find(TextCleaning::Unicode)?;
+        assert_eq!(matches.len(), 2);
+        for (m, original) in matches.iter().zip(["COCAÍNA", "6–MAM"]) {
+            let span = text
+                .chars()
+                .skip(m.start)
+                .take(m.end - m.start)
+                .collect::<String>();
+            assert_eq!(span, original);
+            assert_eq!(m.edits, 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_list() -> Result<()> {
+        let path = std::env::temp_dir().join("drug_extraction_stop_list_test.txt");
+        std::fs::write(
+            &path,
+            "# common misspellings that are real words\nfentanil\n\n  Mixed-Drug  toxicity \n",
+        )?;
+        let stop_list = read_stop_list_from_file(&path, TextCleaning::Ascii)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            stop_list,
+            HashSet::from(["FENTANIL".to_string(), "MIXED-DRUG TOXICITY".to_string()])
+        );
+
+        let terms = vec![SearchTerm {
+            term: "FENTANYL".to_string(),
+            ..Default::default()
+        }];
+        let options = MatchOptions {
+            stop_list,
+            ..Default::default()
+        };
+        let matches = Searcher::new(terms, options)?.find_matches("fentanil and fentanyl");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].matched_term, "FENTANYL");
+        assert_eq!(matches[0].start, 13);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_matches_clean_text() {
+        let text = "Acute (mixed) drug-toxicity; 4-ANPP & fentanyl!";
+        let tokens = tokenize(text)
+            .iter()
+            .map(|t| t.text.to_ascii_uppercase())
+            .collect_vec();
+        assert_eq!(
+            tokens,
+            clean_text(text).split_ascii_whitespace().collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_find_record_matches_ordered_by_column() -> Result<()> {
+        let terms = vec![SearchTerm {
+            term: "HEROIN".to_string(),
+            ..Default::default()
+        }];
+        let options = MatchOptions::default();
+        let searcher = Searcher::new(terms.clone(), options.clone())?;
+        let record = StringRecord::from(vec!["case-1", "heroin", "no drugs", "HEROIN use"]);
+        let columns = vec![
+            ColumnInfo {
+                name: "B".to_string(),
+                index: 1,
+            },
+            ColumnInfo {
+                name: "D".to_string(),
+                index: 3,
+            },
+        ];
+        let id_column = Some(ColumnInfo {
+            name: "A".to_string(),
+            index: 0,
+        });
+        let keep_columns = vec![ColumnInfo {
+            name: "C".to_string(),
+            index: 2,
+        }];
+        let result =
+            find_record_matches(&record, 0, &id_column, &columns, &keep_columns, &searcher)?;
+        assert_eq!(result.id, "case-1");
+        assert_eq!(result.kept_columns, vec!["no drugs"]);
+        assert_eq!(
+            result.matches.iter().map(|(c, _)| *c).collect_vec(),
+            vec![0, 1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_output_in_row_order_for_any_threads() -> Result<()> {
+        let texts = [
+            "acute fentanil toxicity",
+            "gunshot wound",
+            "heroin and cocaine",
+            "no evidence of fentanyl",
+        ];
+        let mut data = "ID,TEXT\n".to_string();
+        for i in 0..RECORDS_PER_CHUNK * 2 + 17 {
+            data.push_str(&format!("{},{}\n", i, texts[i % texts.len()]));
+        }
+        let terms = read_terms_from_file("../data/search_terms.csv", TextCleaning::Ascii)?;
+        let searcher = Searcher::new(terms, MatchOptions::default())?;
+        let run = |threads| -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            let dataset = initialize_dataset(
+                data.as_bytes(),
+                &["text".to_string()],
+                Some("id".to_string()),
+                &[],
+                CsvMatchWriter::new(&mut buf, &[])?,
+            )?;
+            search(dataset, &searcher, threads)?;
+            Ok(buf)
+        };
+        let single = run(1)?;
+        assert!(single.len() > RECORDS_PER_CHUNK * 2 * 20);
+        assert_eq!(run(4)?, single);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_streaming_reader() -> Result<()> {
+        let data = "ID,TEXT\n1,acute fentanyl toxicity\n2,gunshot wound\n";
+        let mut buf = Vec::new();
+        let dataset = initialize_dataset(
+            data.as_bytes(),
+            &["text".to_string()],
+            Some("id".to_string()),
+            &[],
+            CsvMatchWriter::new(&mut buf, &[])?,
+        )?;
+        assert_eq!(dataset.clean_id_column.as_ref().unwrap().index, 0);
+        // rows are never counted, the reader is searched in a single pass
+        assert_eq!(dataset.rows, None);
+        let terms = vec![SearchTerm {
+            term: "FENTANYL".to_string(),
+            ..Default::default()
+        }];
+        let searcher = Searcher::new(terms, MatchOptions::default())?;
+        let summary = search(dataset, &searcher, 1)?;
+        assert_eq!((summary.records, summary.records_with_matches), (2, 1));
+        assert_eq!((summary.search_terms, summary.matched_terms), (1, 1));
+        let text = String::from_utf8(buf)?;
+        let rows = text.lines().skip(1).collect_vec();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].starts_with("1,FENTANYL,FENTANYL,0,"), "{}", rows[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_searcher_bad_column_leaves_no_output() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let data = dir.join("drug_extraction_bad_column_data.csv");
+        let output = dir.join("drug_extraction_bad_column_output.csv");
+        std::fs::write(&data, "ID,TEXT\n1,acute fentanyl toxicity\n")?;
+        if output.exists() {
+            std::fs::remove_file(&output)?;
+        }
+        let result = run_searcher(
+            data.clone(),
+            std::path::PathBuf::from("../data/search_terms.csv"),
+            vec!["NOTES".to_string()],
+            None,
+            output.clone(),
+            false,
+            SearchOptions::default(),
+        );
+        std::fs::remove_file(&data)?;
+        assert!(matches!(result, Err(Error::MissingColumn { .. })));
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_record_read_error() -> Result<()> {
+        let data = "ID,TEXT
+1,acute fentanyl toxicity
+2,gunshot,wound
+";
+        let dataset = initialize_dataset(
+            data.as_bytes(),
+            &["text".to_string()],
+            None,
+            &[],
+            CsvMatchWriter::new(Vec::new(), &[])?,
+        )?;
+        let terms = vec![SearchTerm {
+            term: "FENTANYL".to_string(),
+            ..Default::default()
+        }];
+        let searcher = Searcher::new(terms, MatchOptions::default())?;
+        let result = search(dataset, &searcher, 1);
+        assert!(matches!(result, Err(Error::RecordRead { row: 1, .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_round_trip() -> Result<()> {
+        for extension in ["gz", "zst", "bz2"] {
+            let path = std::env::temp_dir().join(format!(
+                "drug_extraction_compression_test.csv.{}",
+                extension
+            ));
+            {
+                let mut writer = open_output(&path, true)?;
+                writer.write_all(b"ID,TEXT\n1,fentanyl\n2,heroin\n")?;
+                writer.finish()?;
+            }
+            let mut raw = Vec::new();
+            File::open(&path)?.read_to_end(&mut raw)?;
+            assert_eq!(
+                Compression::from_magic(&raw),
+                Compression::from_extension(&path)
+            );
+            assert_eq!(count_rows(&path)?, 2);
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_by_magic_bytes() -> Result<()> {
+        let path = std::env::temp_dir().join("drug_extraction_magic_test.csv.gz");
+        {
+            let mut writer = open_output(&path, true)?;
+            writer.write_all(b"ID,TEXT\n1,fentanyl\n")?;
+            writer.finish()?;
+        }
+        let (mut reader, compression) = decompress_reader(File::open(&path)?, "-")?;
+        std::fs::remove_file(&path)?;
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        assert_eq!(compression, Compression::Gzip);
+        assert_eq!(text, "ID,TEXT\n1,fentanyl\n");
+        Ok(())
+    }
+
     #[test]
     fn test_enumerated_reader() {
         let mut reader = csv::Reader::from_path("../data/search_terms.csv").unwrap();
@@ -1,15 +1,77 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{builder::PossibleValuesParser, Args, Parser, Subcommand};
 use color_eyre::{
     eyre::{eyre, Context},
     Result,
 };
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
-use drug_extraction_cli::run_searcher;
-use std::path::PathBuf;
+use drug_extraction_cli::{
+    matcher_from_name, read_stop_list_from_file, read_terms_from_file, run_searcher, MatchConfig,
+    MatchOptions, NegationRules, OutputFormat, OutputShape, OverlapResolution, SearchOptions,
+    SearchSummary, TextCleaning, DISTANCE_MATCHERS, SIMILARITY_MATCHERS,
+};
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::PathBuf,
+};
 
 fn welcome() {
-    println!("Welcome to the UK-IPOP Drug Extraction tool.");
-    println!("------------------------------------------");
+    eprintln!("Welcome to the UK-IPOP Drug Extraction tool.");
+    eprintln!("------------------------------------------");
+}
+
+/// Percentage of `part` in `total`
+fn percent(part: usize, total: usize) -> f64 {
+    (part as f64 / total as f64) * 100.0
+}
+
+/// Print the totals of a search run to stderr so the output can be piped
+fn report(summary: &SearchSummary) {
+    eprintln!(
+        "Found matches in {:} of {:} records ({:.2}%)",
+        summary.records_with_matches,
+        summary.records,
+        percent(summary.records_with_matches, summary.records)
+    );
+    eprintln!(
+        "Found {:} of {:} search terms ({:.2}%)",
+        summary.matched_terms,
+        summary.search_terms,
+        percent(summary.matched_terms, summary.search_terms)
+    );
+    // nothing is looked up when the cache is disabled or only exact matches are reported
+    if let Some((hits, misses)) = summary.cache_stats.filter(|(h, m)| h + m > 0) {
+        eprintln!(
+            "Match cache hits: {:} of {:} lookups ({:.2}%)",
+            hits,
+            hits + misses,
+            percent(hits, hits + misses)
+        );
+    }
+    if summary.concepts > 0 {
+        eprintln!(
+            "Found {:} of {:} concepts ({:.2}%)",
+            summary.concept_counts.len(),
+            summary.concepts,
+            percent(summary.concept_counts.len(), summary.concepts)
+        );
+        for (concept, match_count, record_count) in &summary.concept_counts {
+            eprintln!(
+                "  {}: {} matches in {} records",
+                concept, match_count, record_count
+            );
+        }
+    }
+    if !summary.tag_counts.is_empty() {
+        eprintln!("Records with matches by metadata tag:");
+        for (tag, record_count) in &summary.tag_counts {
+            eprintln!(
+                "  {}: {} records ({:.2}%)",
+                tag,
+                record_count,
+                percent(*record_count, summary.records)
+            );
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -29,7 +91,7 @@ enum Commands {
     /// Interactive configuration wizard
     Interactive,
     /// File-IO
-    Search(SearchArgs),
+    Search(Box<SearchArgs>),
 }
 
 #[derive(Args, Debug)]
@@ -38,7 +100,7 @@ struct SearchArgs {
     #[arg(short = 't', long, default_value = "search_terms.csv")]
     terms_file: PathBuf,
 
-    /// The dataset file to search
+    /// The dataset file to search, use `-` for stdin
     #[arg(short = 'd', long)]
     data_file: PathBuf,
 
@@ -49,6 +111,138 @@ struct SearchArgs {
     /// The column name in the dataset to keep as identifier [optional]
     #[arg(short, long)]
     id_col: Option<String>,
+
+    /// The column name(s) in the dataset to copy into each output row [optional]
+    #[arg(short = 'k', long, num_args = 1)]
+    keep_cols: Vec<String>,
+
+    /// Only search terms with any of these metadata tags (comma separated) [optional]
+    #[arg(long, value_delimiter = ',')]
+    include_tags: Vec<String>,
+
+    /// Never search terms with any of these metadata tags (comma separated) [optional]
+    #[arg(long, value_delimiter = ',')]
+    exclude_tags: Vec<String>,
+
+    /// The file to write matches to, use `-` for stdout
+    #[arg(short, long, default_value = "output.csv")]
+    output: PathBuf,
+
+    /// Overwrite the output file if it already exists
+    #[arg(short, long)]
+    force: bool,
+
+    /// Include up to this many characters of the original text either side of each match [optional]
+    #[arg(long, value_name = "CHARS")]
+    context: Option<usize>,
+
+    /// A csv file of negation/uncertainty trigger phrases, replacing the built-in list [optional]
+    #[arg(long)]
+    negation_triggers: Option<PathBuf>,
+
+    /// A file of words or phrases, one per line, that are never reported as matches [optional]
+    #[arg(long)]
+    exclusion_list: Option<PathBuf>,
+
+    /// Leave negated matches out of the output
+    #[arg(long)]
+    drop_negated: bool,
+
+    /// Clean text with ASCII rules, or normalize and fold accents with Unicode rules
+    #[arg(long, default_value = "ascii", value_parser = TextCleaning::NAMES)]
+    text_cleaning: String,
+
+    /// Keep only the longest or best scoring of matches that overlap in the same field
+    #[arg(long, default_value = "none", value_parser = OverlapResolution::NAMES)]
+    resolve_overlaps: String,
+
+    /// The format to write matches in
+    #[arg(long, default_value = "csv", value_parser = PossibleValuesParser::new(OutputFormat::NAMES))]
+    output_format: String,
+
+    /// Write one row per match (long) or one row of 0/1 flags per record (wide)
+    #[arg(long, default_value = "long", value_parser = OutputShape::NAMES)]
+    output_shape: String,
+
+    /// The maximum number of edits allowed for a match
+    #[arg(long, default_value_t = MatchConfig::default().max_edits)]
+    max_edits: usize,
+
+    /// The maximum difference in length between a search term and a candidate
+    #[arg(long, default_value_t = MatchConfig::default().length_window)]
+    length_window: usize,
+
+    /// The minimum similarity for each number of edits, starting at one edit (comma separated)
+    #[arg(long, value_delimiter = ',', default_values_t = MatchConfig::default().similarity_cutoffs)]
+    similarity_cutoffs: Vec<f64>,
+
+    /// The algorithm used to count edits
+    #[arg(long, default_value = "osa", value_parser = DISTANCE_MATCHERS)]
+    distance_metric: String,
+
+    /// The algorithm used to score similarity
+    #[arg(long, default_value = "jaro-winkler", value_parser = SIMILARITY_MATCHERS)]
+    similarity_metric: String,
+
+    /// Only report exact matches, skipping fuzzy comparison for a quick screening run
+    #[arg(long)]
+    exact_only: bool,
+
+    /// The number of threads to search with, 0 uses all available cores
+    #[arg(long, default_value_t = SearchOptions::default().threads)]
+    threads: usize,
+
+    /// The number of distinct words and phrases whose matches are cached across records, 0 disables the cache
+    #[arg(long, default_value_t = MatchOptions::default().cache_size)]
+    cache_size: usize,
+
+    /// Read the dataset in a single pass without counting rows first, progress is shown in bytes
+    #[arg(long)]
+    streaming: bool,
+}
+
+impl SearchArgs {
+    /// Collect the run options into [SearchOptions]
+    fn search_options(&self) -> Result<SearchOptions> {
+        let text_cleaning = self.text_cleaning.parse()?;
+        Ok(SearchOptions {
+            matching: MatchOptions {
+                match_config: self.match_config()?,
+                context_window: self.context,
+                negation: match &self.negation_triggers {
+                    Some(path) => NegationRules::from_file(path, text_cleaning)?,
+                    None => NegationRules::builtin(text_cleaning),
+                },
+                drop_negated: self.drop_negated,
+                overlap_resolution: self.resolve_overlaps.parse()?,
+                stop_list: match &self.exclusion_list {
+                    Some(path) => read_stop_list_from_file(path, text_cleaning)?,
+                    None => HashSet::new(),
+                },
+                text_cleaning,
+                cache_size: self.cache_size,
+                exact_only: self.exact_only,
+            },
+            threads: self.threads,
+            streaming: self.streaming,
+            output_format: self.output_format.parse()?,
+            output_shape: self.output_shape.parse()?,
+            keep_columns: self.keep_cols.clone(),
+            include_tags: self.include_tags.clone(),
+            exclude_tags: self.exclude_tags.clone(),
+        })
+    }
+
+    /// Collect the matching algorithms and thresholds into a [MatchConfig]
+    fn match_config(&self) -> Result<MatchConfig> {
+        Ok(MatchConfig {
+            max_edits: self.max_edits,
+            length_window: self.length_window,
+            similarity_cutoffs: self.similarity_cutoffs.clone(),
+            distance_matcher: matcher_from_name(&self.distance_metric)?,
+            similarity_matcher: matcher_from_name(&self.similarity_metric)?,
+        })
+    }
 }
 
 /// Interactive configuration wizard
@@ -66,6 +260,24 @@ fn interactive_wizard() -> Result<SearchArgs> {
         return Err(eyre!("The file {} does not exist.", terms_file.display()));
     }
 
+    let tags = read_terms_from_file(&terms_file, TextCleaning::default())?
+        .iter()
+        .flat_map(|st| st.tags().map(String::from))
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+    let include_tags = if tags.is_empty() {
+        Vec::new()
+    } else {
+        MultiSelect::with_theme(&theme)
+            .with_prompt(">Which metadata tag(s) do you want to restrict the search to? Select none to search every term.\nMultiselect with <Space> and then <Enter> to continue.")
+            .items(&tags)
+            .interact()?
+            .iter()
+            .map(|&x| tags[x].to_string())
+            .collect::<Vec<String>>()
+    };
+
     let data_file: PathBuf = Input::<String>::with_theme(&theme)
         .with_prompt("What is the path to the data file?")
         .interact_text()?
@@ -91,9 +303,9 @@ fn interactive_wizard() -> Result<SearchArgs> {
         .interact()?;
 
     if search_cols.is_empty() {
-        println!("You must select at least one column to search.");
-        println!("Use the arrow keys to select the columns you want to search.");
-        println!("Press `Space` to select and unselect columns and `Enter` to continue.");
+        eprintln!("You must select at least one column to search.");
+        eprintln!("Use the arrow keys to select the columns you want to search.");
+        eprintln!("Press `Space` to select and unselect columns and `Enter` to continue.");
         std::process::exit(1);
     }
     // TODO: this seems backwards going from the index to the name...
@@ -117,11 +329,166 @@ fn interactive_wizard() -> Result<SearchArgs> {
         None
     };
 
+    let keep_cols = MultiSelect::with_theme(&theme)
+        .with_prompt(">Which column(s) do you want to copy into the output? [optional]\nMultiselect with <Space> and then <Enter> to continue.")
+        .items(&headers)
+        .interact()?
+        .iter()
+        .map(|&x| headers[x].to_string())
+        .collect::<Vec<String>>();
+
+    let output: PathBuf = Input::<String>::with_theme(&theme)
+        .with_prompt("Where do you want to save the output?")
+        .default("output.csv".to_string())
+        .interact_text()?
+        .into();
+
+    let output_format_index = Select::with_theme(&theme)
+        .with_prompt("Which format do you want to save the output in?")
+        .items(OutputFormat::NAMES)
+        .default(0)
+        .interact()?;
+
+    let output_shape_index = Select::with_theme(&theme)
+        .with_prompt("Do you want one row per match (long) or one row of flags per record (wide)?")
+        .items(&OutputShape::NAMES)
+        .default(0)
+        .interact()?;
+
+    // confirm before clobbering a previous run, databases are appended to instead
+    let force = if output.exists() && OutputFormat::NAMES[output_format_index] != "sqlite" {
+        let overwrite = Confirm::with_theme(&theme)
+            .with_prompt(format!(
+                "The file {} already exists. Do you want to overwrite it?",
+                output.display()
+            ))
+            .default(false)
+            .interact()?;
+        if !overwrite {
+            return Err(eyre!("The file {} already exists.", output.display()));
+        }
+        true
+    } else {
+        false
+    };
+
+    let exact_only = Confirm::with_theme(&theme)
+        .with_prompt("Do you want to only find exact matches (a quick screening run)?")
+        .default(false)
+        .interact()?;
+
+    let customize_thresholds = !exact_only
+        && Confirm::with_theme(&theme)
+            .with_prompt("Do you want to customize the matching thresholds?")
+            .default(false)
+            .interact()?;
+
+    let match_config = if customize_thresholds {
+        let defaults = MatchConfig::default();
+        let distance_index = Select::with_theme(&theme)
+            .with_prompt("Which algorithm do you want to use to count edits?")
+            .items(&DISTANCE_MATCHERS)
+            .default(0)
+            .interact()?;
+        let similarity_index = Select::with_theme(&theme)
+            .with_prompt("Which algorithm do you want to use to score similarity?")
+            .items(&SIMILARITY_MATCHERS)
+            .default(1)
+            .interact()?;
+        let max_edits = Input::<usize>::with_theme(&theme)
+            .with_prompt("What is the maximum number of edits allowed for a match?")
+            .default(defaults.max_edits)
+            .interact_text()?;
+        let length_window = Input::<usize>::with_theme(&theme)
+            .with_prompt("What is the maximum difference in length between a term and a match?")
+            .default(defaults.length_window)
+            .interact_text()?;
+        let similarity_cutoffs = (1..=max_edits)
+            .map(|edits| {
+                Input::<f64>::with_theme(&theme)
+                    .with_prompt(format!(
+                        "What is the minimum similarity score for a match with {} edit(s)?",
+                        edits
+                    ))
+                    .default(defaults.min_similarity(edits).unwrap_or(0.97))
+                    .interact_text()
+            })
+            .collect::<std::io::Result<Vec<f64>>>()?;
+        MatchConfig {
+            max_edits,
+            length_window,
+            similarity_cutoffs,
+            distance_matcher: matcher_from_name(DISTANCE_MATCHERS[distance_index])?,
+            similarity_matcher: matcher_from_name(SIMILARITY_MATCHERS[similarity_index])?,
+        }
+    } else {
+        MatchConfig::default()
+    };
+
+    let text_cleaning_index = Select::with_theme(&theme)
+        .with_prompt("How do you want to clean the text? (unicode folds accents, e.g. \"cocaína\" to \"cocaina\")")
+        .items(&TextCleaning::NAMES)
+        .default(0)
+        .interact()?;
+
+    let exclusion_list = Input::<String>::with_theme(&theme)
+        .with_prompt("What is the path to a list of words never to report as matches? [optional]")
+        .allow_empty(true)
+        .interact_text()?;
+    let exclusion_list = if exclusion_list.trim().is_empty() {
+        None
+    } else {
+        let path = PathBuf::from(exclusion_list.trim());
+        // confirm that the file exists
+        if !path.exists() {
+            return Err(eyre!("The file {} does not exist.", path.display()));
+        }
+        Some(path)
+    };
+
+    let drop_negated = Confirm::with_theme(&theme)
+        .with_prompt("Do you want to leave out negated matches (e.g. \"no evidence of fentanyl\")?")
+        .default(false)
+        .interact()?;
+
+    let resolve_overlaps_index = Select::with_theme(&theme)
+        .with_prompt("How do you want to resolve overlapping matches (e.g. \"cocaine\" and \"cocaine toxicity\")?")
+        .items(&OverlapResolution::NAMES)
+        .default(0)
+        .interact()?;
+
+    let threads = Input::<usize>::with_theme(&theme)
+        .with_prompt("How many threads do you want to search with? (0 uses all available cores)")
+        .default(SearchOptions::default().threads)
+        .interact_text()?;
+
     let args = SearchArgs {
         terms_file,
         data_file,
         id_col,
         search_cols,
+        keep_cols,
+        include_tags,
+        exclude_tags: Vec::new(),
+        output,
+        force,
+        context: MatchOptions::default().context_window,
+        negation_triggers: None,
+        exclusion_list,
+        drop_negated,
+        text_cleaning: TextCleaning::NAMES[text_cleaning_index].to_string(),
+        resolve_overlaps: OverlapResolution::NAMES[resolve_overlaps_index].to_string(),
+        output_format: OutputFormat::NAMES[output_format_index].to_string(),
+        output_shape: OutputShape::NAMES[output_shape_index].to_string(),
+        max_edits: match_config.max_edits,
+        length_window: match_config.length_window,
+        similarity_cutoffs: match_config.similarity_cutoffs,
+        distance_metric: match_config.distance_matcher.name().to_string(),
+        similarity_metric: match_config.similarity_matcher.name().to_string(),
+        exact_only,
+        threads,
+        cache_size: MatchOptions::default().cache_size,
+        streaming: SearchOptions::default().streaming,
     };
 
     Ok(args)
@@ -134,24 +501,20 @@ fn main() -> Result<()> {
 
     welcome();
 
-    match cli.command {
-        Commands::Interactive => {
-            let args = interactive_wizard()?;
-            run_searcher(
-                args.data_file,
-                args.terms_file,
-                args.search_cols,
-                args.id_col,
-            )?;
-        }
-        Commands::Search(args) => {
-            run_searcher(
-                args.data_file,
-                args.terms_file,
-                args.search_cols,
-                args.id_col,
-            )?;
-        }
-    }
+    let args = match cli.command {
+        Commands::Interactive => interactive_wizard()?,
+        Commands::Search(args) => *args,
+    };
+    let options = args.search_options()?;
+    let summary = run_searcher(
+        args.data_file,
+        args.terms_file,
+        args.search_cols,
+        args.id_col,
+        args.output,
+        args.force,
+        options,
+    )?;
+    report(&summary);
     Ok(())
 }
@@ -0,0 +1,209 @@
+use std::{fmt::Debug, sync::Arc};
+
+use crate::{Error, Result};
+
+/// The kind of score a [Matcher] produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatcherKind {
+    /// A count of edits, lower is better
+    EditDistance,
+    /// A score between 0 and 1, higher is better
+    Similarity,
+}
+
+/// Trait for string comparison algorithms used to compare a search term to a candidate
+/// Implement this to plug a custom algorithm into a [crate::MatchConfig].
+pub trait Matcher: Debug + Send + Sync {
+    /// The name of the algorithm, as used on the command line
+    fn name(&self) -> &'static str;
+    /// The kind of score returned by [Matcher::score]
+    fn kind(&self) -> MatcherKind;
+    /// Compare two strings
+    fn score(&self, a: &str, b: &str) -> f64;
+}
+
+/// Optimal string alignment distance
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Osa;
+
+impl Matcher for Osa {
+    fn name(&self) -> &'static str {
+        "osa"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::EditDistance
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::osa_distance(a, b) as f64
+    }
+}
+
+/// True Damerau-Levenshtein distance, allowing substrings to be edited more than once
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DamerauLevenshtein;
+
+impl Matcher for DamerauLevenshtein {
+    fn name(&self) -> &'static str {
+        "damerau-levenshtein"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::EditDistance
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::damerau_levenshtein(a, b) as f64
+    }
+}
+
+/// Levenshtein distance, no transpositions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Levenshtein;
+
+impl Matcher for Levenshtein {
+    fn name(&self) -> &'static str {
+        "levenshtein"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::EditDistance
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::levenshtein(a, b) as f64
+    }
+}
+
+/// Jaro similarity
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Jaro;
+
+impl Matcher for Jaro {
+    fn name(&self) -> &'static str {
+        "jaro"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::Similarity
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::jaro(a, b)
+    }
+}
+
+/// Jaro-Winkler similarity, favoring strings with a common prefix
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JaroWinkler;
+
+impl Matcher for JaroWinkler {
+    fn name(&self) -> &'static str {
+        "jaro-winkler"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::Similarity
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::jaro_winkler(a, b)
+    }
+}
+
+/// Levenshtein distance normalized by the length of the longer string
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NormalizedLevenshtein;
+
+impl Matcher for NormalizedLevenshtein {
+    fn name(&self) -> &'static str {
+        "normalized-levenshtein"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::Similarity
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::normalized_levenshtein(a, b)
+    }
+}
+
+/// Sørensen-Dice similarity over character bigrams
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SorensenDice;
+
+impl Matcher for SorensenDice {
+    fn name(&self) -> &'static str {
+        "sorensen-dice"
+    }
+    fn kind(&self) -> MatcherKind {
+        MatcherKind::Similarity
+    }
+    fn score(&self, a: &str, b: &str) -> f64 {
+        strsim::sorensen_dice(a, b)
+    }
+}
+
+/// Names of the built-in edit distance algorithms
+pub const DISTANCE_MATCHERS: [&str; 3] = ["osa", "damerau-levenshtein", "levenshtein"];
+
+/// Names of the built-in similarity algorithms
+pub const SIMILARITY_MATCHERS: [&str; 4] = [
+    "jaro",
+    "jaro-winkler",
+    "normalized-levenshtein",
+    "sorensen-dice",
+];
+
+/// Function to look up a built-in [Matcher] by name
+/// Example:
+/// ```
+/// use drug_extraction_cli::{matcher_from_name, MatcherKind};
+///
+/// let matcher = matcher_from_name("levenshtein").unwrap();
+/// assert_eq!(matcher.kind(), MatcherKind::EditDistance);
+/// assert_eq!(matcher.score("FENTANYL", "FENTANIL"), 1.0);
+/// assert!(matcher_from_name("hamming").is_err());
+/// ```
+pub fn matcher_from_name(name: &str) -> Result<Arc<dyn Matcher>> {
+    let matcher: Arc<dyn Matcher> = match name {
+        "osa" => Arc::new(Osa),
+        "damerau-levenshtein" => Arc::new(DamerauLevenshtein),
+        "levenshtein" => Arc::new(Levenshtein),
+        "jaro" => Arc::new(Jaro),
+        "jaro-winkler" => Arc::new(JaroWinkler),
+        "normalized-levenshtein" => Arc::new(NormalizedLevenshtein),
+        "sorensen-dice" => Arc::new(SorensenDice),
+        _ => {
+            return Err(Error::InvalidOption(format!(
+                "Unknown string similarity algorithm {}",
+                name
+            )))
+        }
+    };
+    Ok(matcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matcher_names_round_trip() {
+        for name in DISTANCE_MATCHERS {
+            let matcher = matcher_from_name(name).unwrap();
+            assert_eq!(matcher.name(), name);
+            assert_eq!(matcher.kind(), MatcherKind::EditDistance);
+        }
+        for name in SIMILARITY_MATCHERS {
+            let matcher = matcher_from_name(name).unwrap();
+            assert_eq!(matcher.name(), name);
+            assert_eq!(matcher.kind(), MatcherKind::Similarity);
+        }
+    }
+
+    #[test]
+    fn test_osa_vs_damerau_levenshtein() {
+        // osa cannot edit a substring twice, true damerau-levenshtein can
+        assert_eq!(Osa.score("CA", "ABC"), 3.0);
+        assert_eq!(DamerauLevenshtein.score("CA", "ABC"), 2.0);
+    }
+
+    #[test]
+    fn test_similarities_identical() {
+        for name in SIMILARITY_MATCHERS {
+            let matcher = matcher_from_name(name).unwrap();
+            assert_eq!(matcher.score("COCAINE", "COCAINE"), 1.0);
+        }
+    }
+}
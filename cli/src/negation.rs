@@ -0,0 +1,335 @@
+use itertools::Itertools;
+use serde::Deserialize;
+
+use std::{fs::File, path::Path};
+
+use crate::{deserialize_lines, error::IoContext, Error, Result, TextCleaning, Token};
+
+/// The role of a trigger phrase in NegEx-style negation detection
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TriggerKind {
+    /// Negates matches that follow it, e.g. "no evidence of"
+    PreNegation,
+    /// Negates matches that precede it, e.g. "not detected"
+    PostNegation,
+    /// Marks matches that follow it as uncertain, e.g. "possible"
+    PreUncertainty,
+    /// Marks matches that precede it as uncertain, e.g. "suspected"
+    PostUncertainty,
+    /// Looks like a trigger but is not one and is skipped, e.g. "no increase"
+    Pseudo,
+    /// Ends the scope of a trigger, e.g. "but"
+    Termination,
+}
+
+/// A trigger phrase, as read from a triggers file
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Trigger {
+    pub phrase: String,
+    pub kind: TriggerKind,
+}
+
+/// Built-in trigger phrases, used unless a triggers file is given
+const DEFAULT_TRIGGERS: [(&str, TriggerKind); 47] = [
+    ("no", TriggerKind::PreNegation),
+    ("not", TriggerKind::PreNegation),
+    ("without", TriggerKind::PreNegation),
+    ("denies", TriggerKind::PreNegation),
+    ("denied", TriggerKind::PreNegation),
+    ("never", TriggerKind::PreNegation),
+    ("none", TriggerKind::PreNegation),
+    ("negative for", TriggerKind::PreNegation),
+    ("free of", TriggerKind::PreNegation),
+    ("absence of", TriggerKind::PreNegation),
+    ("ruled out", TriggerKind::PreNegation),
+    ("rules out", TriggerKind::PreNegation),
+    ("negative", TriggerKind::PostNegation),
+    ("not detected", TriggerKind::PostNegation),
+    ("none detected", TriggerKind::PostNegation),
+    ("not present", TriggerKind::PostNegation),
+    ("not found", TriggerKind::PostNegation),
+    ("absent", TriggerKind::PostNegation),
+    ("ruled out", TriggerKind::PostNegation),
+    ("was ruled out", TriggerKind::PostNegation),
+    ("possible", TriggerKind::PreUncertainty),
+    ("possibly", TriggerKind::PreUncertainty),
+    ("probable", TriggerKind::PreUncertainty),
+    ("probably", TriggerKind::PreUncertainty),
+    ("suspected", TriggerKind::PreUncertainty),
+    ("suspicion of", TriggerKind::PreUncertainty),
+    ("questionable", TriggerKind::PreUncertainty),
+    ("presumed", TriggerKind::PreUncertainty),
+    ("rule out", TriggerKind::PreUncertainty),
+    // "may" alone is also the month, as in "died May 5"
+    ("may have", TriggerKind::PreUncertainty),
+    ("suspected", TriggerKind::PostUncertainty),
+    ("possible", TriggerKind::PostUncertainty),
+    ("pending", TriggerKind::PostUncertainty),
+    ("not excluded", TriggerKind::PostUncertainty),
+    ("cannot be excluded", TriggerKind::PostUncertainty),
+    ("no increase", TriggerKind::Pseudo),
+    ("no change", TriggerKind::Pseudo),
+    ("not only", TriggerKind::Pseudo),
+    ("not certain if", TriggerKind::Pseudo),
+    ("without difficulty", TriggerKind::Pseudo),
+    ("gram negative", TriggerKind::Pseudo),
+    ("but", TriggerKind::Termination),
+    ("however", TriggerKind::Termination),
+    ("although", TriggerKind::Termination),
+    ("except", TriggerKind::Termination),
+    ("aside from", TriggerKind::Termination),
+    ("which", TriggerKind::Termination),
+];
+
+/// Characters that end a sentence, trigger scopes never cross them
+const SENTENCE_BREAKS: [char; 6] = ['.', ';', ':', '!', '?', '\n'];
+
+/// Whether a match is negated or uncertain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Assertion {
+    pub negated: bool,
+    pub uncertain: bool,
+}
+
+/// Struct to hold the trigger phrases used to detect negated and uncertain matches
+/// Triggers are looked for within `window` words either side of a match and within the same sentence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegationRules {
+    /// trigger phrases split into uppercase words, indexed by [TriggerKind]
+    triggers: [Vec<Vec<String>>; 6],
+    /// The number of words either side of a match that are searched for triggers
+    pub window: usize,
+}
+
+impl Default for NegationRules {
+    fn default() -> Self {
+        NegationRules::builtin(TextCleaning::default())
+    }
+}
+
+impl NegationRules {
+    /// Create rules from a list of trigger phrases, cleaned the same way as the text
+    pub fn new(triggers: Vec<Trigger>, cleaning: TextCleaning) -> Self {
+        let mut by_kind: [Vec<Vec<String>>; 6] = Default::default();
+        for trigger in triggers {
+            let words = cleaning
+                .clean(&trigger.phrase)
+                .split_ascii_whitespace()
+                .map(|w| w.to_string())
+                .collect_vec();
+            if !words.is_empty() {
+                by_kind[trigger.kind as usize].push(words);
+            }
+        }
+        NegationRules {
+            triggers: by_kind,
+            window: 5,
+        }
+    }
+
+    /// Create rules from the built-in English trigger phrases
+    pub fn builtin(cleaning: TextCleaning) -> Self {
+        NegationRules::new(
+            DEFAULT_TRIGGERS
+                .iter()
+                .map(|(phrase, kind)| Trigger {
+                    phrase: phrase.to_string(),
+                    kind: *kind,
+                })
+                .collect(),
+            cleaning,
+        )
+    }
+
+    /// Read trigger phrases from a csv file with `phrase` and `kind` columns
+    /// `kind` is one of `pre-negation`, `post-negation`, `pre-uncertainty`, `post-uncertainty`,
+    /// `pseudo` or `termination`.
+    pub fn from_file<P: AsRef<Path>>(p: P, cleaning: TextCleaning) -> Result<Self> {
+        let file = File::open(p).io_context("Unable to read negation triggers file")?;
+        let rdr = csv::Reader::from_reader(file);
+        let triggers =
+            deserialize_lines(rdr, |line, message| Error::TriggerParse { line, message })
+                .map_ok(|(_, trigger)| trigger)
+                .collect::<Result<Vec<Trigger>>>()?;
+        Ok(NegationRules::new(triggers, cleaning))
+    }
+
+    /// The triggers of a given kind
+    fn of_kind(&self, kind: TriggerKind) -> impl Iterator<Item = &Vec<String>> {
+        self.triggers[kind as usize].iter()
+    }
+
+    /// Whether a trigger of the given kind starts at word `start`
+    fn starts_at(&self, tokens: &[Token], start: usize, kind: TriggerKind) -> bool {
+        self.of_kind(kind)
+            .any(|words| phrase_at(tokens, start, words))
+    }
+
+    /// Whether a trigger of the given kind ends at word `end`
+    fn ends_at(&self, tokens: &[Token], end: usize, kind: TriggerKind) -> bool {
+        self.of_kind(kind)
+            .any(|words| words.len() <= end + 1 && phrase_at(tokens, end + 1 - words.len(), words))
+    }
+
+    /// Whether word `i` is part of a pseudo trigger
+    fn in_pseudo(&self, tokens: &[Token], i: usize) -> bool {
+        self.of_kind(TriggerKind::Pseudo).any(|words| {
+            (i.saturating_sub(words.len() - 1)..=i).any(|start| phrase_at(tokens, start, words))
+        })
+    }
+
+    /// Assess the match spanning words `start..end` of a piece of text
+    /// `breaks[i]` is whether a sentence ends between word `i - 1` and word `i`, see [sentence_breaks].
+    pub fn assess(&self, tokens: &[Token], breaks: &[bool], start: usize, end: usize) -> Assertion {
+        let mut assertion = Assertion::default();
+        // look back from the match for pre triggers
+        for i in (start.saturating_sub(self.window)..start).rev() {
+            if breaks[i + 1] || self.ends_at(tokens, i, TriggerKind::Termination) {
+                break;
+            }
+            if self.in_pseudo(tokens, i) {
+                continue;
+            }
+            assertion.negated |= self.ends_at(tokens, i, TriggerKind::PreNegation);
+            assertion.uncertain |= self.ends_at(tokens, i, TriggerKind::PreUncertainty);
+        }
+        // look forward from the match for post triggers
+        for (i, &sentence_break) in breaks.iter().enumerate().skip(end).take(self.window) {
+            if sentence_break || self.starts_at(tokens, i, TriggerKind::Termination) {
+                break;
+            }
+            if self.in_pseudo(tokens, i) {
+                continue;
+            }
+            assertion.negated |= self.starts_at(tokens, i, TriggerKind::PostNegation);
+            assertion.uncertain |= self.starts_at(tokens, i, TriggerKind::PostUncertainty);
+        }
+        assertion
+    }
+}
+
+/// Whether the words starting at `start` are the words of a phrase
+fn phrase_at(tokens: &[Token], start: usize, words: &[String]) -> bool {
+    tokens
+        .get(start..start + words.len())
+        .is_some_and(|window| {
+            window
+                .iter()
+                .zip(words)
+                .all(|(t, w)| t.text.eq_ignore_ascii_case(w))
+        })
+}
+
+/// Function to find the sentence breaks between the words of a piece of text
+/// The result has one entry per word, true when a sentence ends between that word and the one before it.
+/// Example:
+/// ```
+/// use drug_extraction_cli::{sentence_breaks, tokenize};
+///
+/// let text = "No trauma. Fentanyl toxicity";
+/// let tokens = tokenize(text);
+/// assert_eq!(sentence_breaks(text, &tokens), vec![false, false, true, false]);
+/// ```
+pub fn sentence_breaks(text: &str, tokens: &[Token]) -> Vec<bool> {
+    let break_offsets = text
+        .chars()
+        .enumerate()
+        .filter(|(_, c)| SENTENCE_BREAKS.contains(c))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    let mut breaks = vec![false; tokens.len()];
+    for i in 1..tokens.len() {
+        let gap_start = break_offsets.partition_point(|&o| o < tokens[i - 1].end);
+        breaks[i] = break_offsets
+            .get(gap_start)
+            .is_some_and(|&o| o < tokens[i].start);
+    }
+    breaks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenize;
+    use color_eyre::Result;
+
+    /// Assess the first occurrence of `word` in `text`
+    fn assess(text: &str, word: &str) -> Assertion {
+        let tokens = tokenize(text);
+        let breaks = sentence_breaks(text, &tokens);
+        let start = tokens
+            .iter()
+            .position(|t| t.text.eq_ignore_ascii_case(word))
+            .unwrap();
+        NegationRules::default().assess(&tokens, &breaks, start, start + 1)
+    }
+
+    #[test]
+    fn test_pre_and_post_negation() {
+        assert!(assess("No evidence of fentanyl use", "fentanyl").negated);
+        assert!(assess("Toxicology negative for cocaine", "cocaine").negated);
+        assert!(assess("Cocaine was not detected", "cocaine").negated);
+        assert_eq!(
+            assess("Acute fentanyl toxicity", "fentanyl"),
+            Assertion::default()
+        );
+    }
+
+    #[test]
+    fn test_uncertainty() {
+        let assertion = assess("Possible heroin overdose", "heroin");
+        assert!(assertion.uncertain);
+        assert!(!assertion.negated);
+        assert!(assess("Heroin use suspected", "heroin").uncertain);
+        assert!(assess("He may have injected heroin", "heroin").uncertain);
+        assert_eq!(
+            assess("Died May 5 of acute fentanyl toxicity", "fentanyl"),
+            Assertion::default()
+        );
+    }
+
+    #[test]
+    fn test_scope_ends_at_sentence_and_termination() {
+        assert!(!assess("No trauma. Fentanyl toxicity", "fentanyl").negated);
+        assert!(!assess("No alcohol but cocaine present", "cocaine").negated);
+        assert!(!assess("No increase in morphine levels", "morphine").negated);
+        assert!(!assess("No one two three four five six heroin", "heroin").negated);
+    }
+
+    #[test]
+    fn test_triggers_cleaned_like_text() {
+        let text = "Fentanilo no se detectó";
+        let (upper_text, _) = TextCleaning::Unicode.normalize(text, true);
+        let tokens = TextCleaning::Unicode.tokenize(&upper_text);
+        let breaks = sentence_breaks(&upper_text, &tokens);
+        let triggers = vec![Trigger {
+            phrase: "no se detectó".to_string(),
+            kind: TriggerKind::PostNegation,
+        }];
+        let rules = NegationRules::new(triggers.clone(), TextCleaning::Unicode);
+        assert!(rules.assess(&tokens, &breaks, 0, 1).negated);
+        let rules = NegationRules::new(triggers, TextCleaning::Ascii);
+        assert!(!rules.assess(&tokens, &breaks, 0, 1).negated);
+    }
+
+    #[test]
+    fn test_from_file_reports_line() -> Result<()> {
+        let path = std::env::temp_dir().join("drug_extraction_triggers_test.csv");
+        std::fs::write(
+            &path,
+            "phrase,kind
+not detected,post-negation
+maybe,perhaps
+",
+        )?;
+        let result = NegationRules::from_file(&path, TextCleaning::Ascii);
+        std::fs::remove_file(&path)?;
+        assert!(
+            matches!(result, Err(Error::TriggerParse { line: 3, .. })),
+            "{:?}",
+            result
+        );
+        Ok(())
+    }
+}
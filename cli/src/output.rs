@@ -0,0 +1,618 @@
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{self, Write},
+    str::FromStr,
+};
+
+use crate::{
+    error::ErrorContext, metadata_tags, Error, FinishWrite, Result, SearchOutput, SearchTerm,
+};
+
+/// The columns of [SearchOutput], in output order
+pub const OUTPUT_FIELDS: [&str; 13] = [
+    "row_id",
+    "search_term",
+    "matched_term",
+    "edits",
+    "similarity_score",
+    "search_field",
+    "metadata",
+    "start",
+    "end",
+    "context",
+    "negated",
+    "uncertain",
+    "canonical",
+];
+
+/// Struct to hold the record-level output shared by every match in a record
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordOutput<'a> {
+    /// The row id of the record, either from specified column or line number
+    pub row_id: &'a str,
+    /// The values of the columns kept from the dataset, in the same order as their names
+    pub kept_columns: &'a [String],
+}
+
+/// Trait for the output sinks that search results are written to
+/// Results are written one record at a time, including records without any matches,
+/// so that sinks can produce both match-level and record-level output.
+pub trait MatchWriter {
+    /// Write the matches found in a single record
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()>;
+    /// Flush any buffered output once the search is complete
+    fn finish(&mut self) -> Result<()>;
+}
+
+impl<M: MatchWriter + ?Sized> MatchWriter for Box<M> {
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()> {
+        (**self).write_record(record, matches)
+    }
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Supported output formats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Comma separated values, one row per match
+    #[default]
+    Csv,
+    /// JSON Lines, one object per match
+    Jsonl,
+    /// Parquet, typed columns, one row per match
+    #[cfg(feature = "arrow")]
+    Parquet,
+    /// Arrow IPC file, typed columns, one row per match
+    #[cfg(feature = "arrow")]
+    Arrow,
+    /// SQLite database, appended to across runs
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl OutputFormat {
+    /// Names of the output formats, as used on the command line
+    pub const NAMES: &'static [&'static str] = &[
+        "csv",
+        "jsonl",
+        #[cfg(feature = "arrow")]
+        "parquet",
+        #[cfg(feature = "arrow")]
+        "arrow",
+        #[cfg(feature = "sqlite")]
+        "sqlite",
+    ];
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            #[cfg(feature = "arrow")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            #[cfg(feature = "arrow")]
+            "arrow" => Ok(OutputFormat::Arrow),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(OutputFormat::Sqlite),
+            _ => Err(Error::InvalidOption(format!("Unknown output format {}", s))),
+        }
+    }
+}
+
+/// Supported output shapes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputShape {
+    /// One row per match
+    #[default]
+    Long,
+    /// One row per record with a 0/1 flag column per term, search field and metadata tag
+    Wide,
+}
+
+impl OutputShape {
+    /// Names of the output shapes, as used on the command line
+    pub const NAMES: [&'static str; 2] = ["long", "wide"];
+}
+
+impl FromStr for OutputShape {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "long" => Ok(OutputShape::Long),
+            "wide" => Ok(OutputShape::Wide),
+            _ => Err(Error::InvalidOption(format!("Unknown output shape {}", s))),
+        }
+    }
+}
+
+/// Writes one csv row per match, followed by any kept columns
+#[derive(Debug)]
+pub struct CsvMatchWriter<W: FinishWrite> {
+    /// taken when the writer is finished
+    writer: Option<csv::Writer<W>>,
+}
+
+impl<W: FinishWrite> CsvMatchWriter<W> {
+    /// Create a writer and write the header
+    pub fn new(output: W, kept_columns: &[String]) -> Result<Self> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(output);
+        writer
+            .write_record(
+                OUTPUT_FIELDS
+                    .iter()
+                    .copied()
+                    .chain(kept_columns.iter().map(|c| c.as_str())),
+            )
+            .writer_context("Unable to write output header")?;
+        Ok(CsvMatchWriter {
+            writer: Some(writer),
+        })
+    }
+}
+
+impl<W: FinishWrite> MatchWriter for CsvMatchWriter<W> {
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()> {
+        let writer = unfinished(&mut self.writer)?;
+        for m in matches {
+            writer
+                .serialize((m, record.kept_columns))
+                .writer_context("Unable to serialize output")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        finish_csv(&mut self.writer)
+    }
+}
+
+/// The csv writer of a match writer that has not been finished
+fn unfinished<W: Write>(writer: &mut Option<csv::Writer<W>>) -> Result<&mut csv::Writer<W>> {
+    writer
+        .as_mut()
+        .ok_or_else(|| io::Error::other("the writer has already been finished"))
+        .writer_context("Unable to write output")
+}
+
+/// Flush a csv writer and finish its output, leaving it finished
+fn finish_csv<W: FinishWrite>(writer: &mut Option<csv::Writer<W>>) -> Result<()> {
+    let Some(writer) = writer.take() else {
+        return Ok(());
+    };
+    let mut output = writer
+        .into_inner()
+        .map_err(|e| io::Error::new(e.error().kind(), e.error().to_string()))
+        .writer_context("Unable to flush writer")?;
+    output.finish().writer_context("Unable to finish output")
+}
+
+/// Kept column names paired with their values, serialized as a map in column order
+#[derive(Debug, Clone, PartialEq)]
+struct KeptColumns<'a> {
+    names: &'a [String],
+    values: &'a [String],
+}
+
+impl<'a> Serialize for KeptColumns<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.names.len()))?;
+        for (name, value) in self.names.iter().zip(self.values) {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+/// A match as written to JSON Lines, with the metadata split into a list
+/// and the kept columns as extra keys
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct JsonOutput<'a> {
+    row_id: &'a str,
+    search_term: &'a str,
+    matched_term: &'a str,
+    edits: usize,
+    similarity_score: f64,
+    search_field: &'a str,
+    metadata: Vec<&'a str>,
+    start: usize,
+    end: usize,
+    context: Option<&'a str>,
+    negated: bool,
+    uncertain: bool,
+    canonical: &'a str,
+    #[serde(flatten)]
+    kept_columns: KeptColumns<'a>,
+}
+
+impl<'a> JsonOutput<'a> {
+    fn new(m: &'a SearchOutput<'a>, kept_columns: KeptColumns<'a>) -> Self {
+        JsonOutput {
+            row_id: m.row_id,
+            search_term: m.search_term,
+            matched_term: m.matched_term,
+            edits: m.edits,
+            similarity_score: m.similarity_score,
+            search_field: m.search_field,
+            metadata: m
+                .metadata
+                .as_deref()
+                .map(|meta| metadata_tags(meta).collect())
+                .unwrap_or_default(),
+            start: m.start,
+            end: m.end,
+            context: m.context,
+            negated: m.negated,
+            uncertain: m.uncertain,
+            canonical: m.canonical,
+            kept_columns,
+        }
+    }
+}
+
+/// Writes one JSON object per line per match
+#[derive(Debug)]
+pub struct JsonlMatchWriter<W: FinishWrite> {
+    writer: W,
+    kept_columns: Vec<String>,
+}
+
+impl<W: FinishWrite> JsonlMatchWriter<W> {
+    pub fn new(output: W, kept_columns: &[String]) -> Self {
+        JsonlMatchWriter {
+            writer: output,
+            kept_columns: kept_columns.to_vec(),
+        }
+    }
+}
+
+impl<W: FinishWrite> MatchWriter for JsonlMatchWriter<W> {
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()> {
+        for m in matches {
+            let kept_columns = KeptColumns {
+                names: &self.kept_columns,
+                values: record.kept_columns,
+            };
+            serde_json::to_writer(&mut self.writer, &JsonOutput::new(m, kept_columns))
+                .writer_context("Unable to serialize output")?;
+            self.writer
+                .write_all(b"\n")
+                .writer_context("Unable to write output")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer
+            .finish()
+            .writer_context("Unable to finish output")
+    }
+}
+
+/// Writes one csv row per record with any kept columns followed by 0/1 flags,
+/// including records without any matches
+/// The flag columns are known up front from the search terms and search fields,
+/// so records are streamed straight to the output without being held in memory.
+#[derive(Debug)]
+pub struct WideMatchWriter<W: FinishWrite> {
+    /// taken when the writer is finished
+    writer: Option<csv::Writer<W>>,
+    /// flag columns for each search term
+    term_columns: HashMap<String, usize>,
+    /// flag columns for each search field, `<FIELD>_matched`
+    field_columns: HashMap<String, usize>,
+    /// flag columns for each metadata tag, `<TAG>_meta`
+    tag_columns: HashMap<String, usize>,
+    flags: Vec<u8>,
+}
+
+impl<W: FinishWrite> WideMatchWriter<W> {
+    /// Create a writer and write the header
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::{MatchWriter, RecordOutput, SearchTerm, WideMatchWriter};
+    ///
+    /// let terms = vec![SearchTerm {
+    ///     term: "HEROIN".to_string(),
+    ///     metadata: Some("drug|opiate".to_string()),
+    ///     ..Default::default()
+    /// }];
+    /// let mut out = Vec::new();
+    /// let fields = vec!["PRIMARY CAUSE".to_string()];
+    /// let mut writer = WideMatchWriter::new(&mut out, &terms, &fields, &[]).unwrap();
+    /// let record = RecordOutput {
+    ///     row_id: "1",
+    ///     kept_columns: &[],
+    /// };
+    /// writer.write_record(&record, &[]).unwrap();
+    /// writer.finish().unwrap();
+    /// drop(writer);
+    /// let text = String::from_utf8(out).unwrap();
+    /// assert_eq!(text, "row_id,HEROIN,PRIMARY_CAUSE_matched,DRUG_meta,OPIATE_meta\n1,0,0,0,0\n");
+    /// ```
+    pub fn new(
+        output: W,
+        search_terms: &[SearchTerm],
+        search_fields: &[String],
+        kept_columns: &[String],
+    ) -> Result<Self> {
+        let mut header = Vec::new();
+        let mut term_columns = HashMap::new();
+        for term in search_terms {
+            add_flag_column(
+                &mut header,
+                &mut term_columns,
+                &term.term,
+                term.term.clone(),
+            );
+        }
+        let mut field_columns = HashMap::new();
+        for field in search_fields {
+            let name = format!("{}_matched", field.replace(' ', "_"));
+            add_flag_column(&mut header, &mut field_columns, field, name);
+        }
+        let mut tag_columns = HashMap::new();
+        for tag in search_terms.iter().flat_map(|st| st.tags()) {
+            let tag = tag.to_ascii_uppercase();
+            let name = format!("{}_meta", tag);
+            add_flag_column(&mut header, &mut tag_columns, &tag, name);
+        }
+        let mut writer = csv::Writer::from_writer(output);
+        writer
+            .write_record(
+                std::iter::once("row_id")
+                    .chain(kept_columns.iter().map(|c| c.as_str()))
+                    .chain(header.iter().map(|c| c.as_str())),
+            )
+            .writer_context("Unable to write output header")?;
+        Ok(WideMatchWriter {
+            writer: Some(writer),
+            term_columns,
+            field_columns,
+            tag_columns,
+            flags: vec![0; header.len()],
+        })
+    }
+}
+
+impl<W: FinishWrite> MatchWriter for WideMatchWriter<W> {
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()> {
+        self.flags.iter_mut().for_each(|f| *f = 0);
+        for m in matches {
+            let columns = [
+                self.term_columns.get(m.search_term),
+                self.field_columns.get(m.search_field),
+            ];
+            let tags = m
+                .metadata
+                .as_deref()
+                .into_iter()
+                .flat_map(metadata_tags)
+                .map(|tag| self.tag_columns.get(&tag.to_ascii_uppercase()));
+            for column in columns.into_iter().chain(tags).flatten() {
+                self.flags[*column] = 1;
+            }
+        }
+        let writer = unfinished(&mut self.writer)?;
+        writer
+            .write_field(record.row_id)
+            .writer_context("Unable to write output")?;
+        for value in record.kept_columns {
+            writer
+                .write_field(value)
+                .writer_context("Unable to write output")?;
+        }
+        writer
+            .write_record(self.flags.iter().map(|f| f.to_string()))
+            .writer_context("Unable to write output")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        finish_csv(&mut self.writer)
+    }
+}
+
+/// Add a flag column to the header the first time a key is seen
+/// Flag columns are indexed from after the `row_id` and kept columns.
+fn add_flag_column(
+    header: &mut Vec<String>,
+    columns: &mut HashMap<String, usize>,
+    key: &str,
+    name: String,
+) {
+    if let Entry::Vacant(e) = columns.entry(key.to_string()) {
+        e.insert(header.len());
+        header.push(name);
+    }
+}
+
+/// Function to initialize the [MatchWriter] for an output format
+/// `kept_columns` are the names of the dataset columns copied into each output row.
+pub fn initialize_writer<W: FinishWrite + Send + 'static>(
+    output: W,
+    format: OutputFormat,
+    kept_columns: &[String],
+) -> Result<Box<dyn MatchWriter>> {
+    let writer: Box<dyn MatchWriter> = match format {
+        OutputFormat::Csv => Box::new(CsvMatchWriter::new(output, kept_columns)?),
+        OutputFormat::Jsonl => Box::new(JsonlMatchWriter::new(
+            std::io::BufWriter::new(output),
+            kept_columns,
+        )),
+        #[cfg(feature = "arrow")]
+        OutputFormat::Parquet => {
+            Box::new(crate::ColumnarMatchWriter::parquet(output, kept_columns)?)
+        }
+        #[cfg(feature = "arrow")]
+        OutputFormat::Arrow => {
+            Box::new(crate::ColumnarMatchWriter::arrow_ipc(output, kept_columns)?)
+        }
+        #[cfg(feature = "sqlite")]
+        OutputFormat::Sqlite => {
+            return Err(Error::InvalidOption(
+                "The sqlite output format writes to a database file, see SqliteMatchWriter"
+                    .to_string(),
+            ))
+        }
+    };
+    Ok(writer)
+}
+
+/// An exact match of HEROIN in the TEXT field of row 1, for writer tests to override fields of
+#[cfg(test)]
+pub(crate) fn heroin_match() -> SearchOutput<'static> {
+    SearchOutput {
+        row_id: "1",
+        search_term: "HEROIN",
+        matched_term: "HEROIN",
+        edits: 0,
+        similarity_score: 1.0,
+        search_field: "TEXT",
+        metadata: &None,
+        start: 0,
+        end: 6,
+        context: None,
+        negated: false,
+        uncertain: false,
+        canonical: "HEROIN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::Result;
+
+    #[test]
+    fn test_jsonl_splits_metadata() -> Result<()> {
+        let metadata = Some("drug|fentanyl|opiate".to_string());
+        let m = SearchOutput {
+            search_term: "FENTANYL",
+            matched_term: "FENTANIL",
+            edits: 1,
+            metadata: &metadata,
+            canonical: "FENTANYL",
+            ..heroin_match()
+        };
+        let kept_columns = vec!["AGE".to_string()];
+        let mut buf = Vec::new();
+        {
+            let mut writer = JsonlMatchWriter::new(&mut buf, &kept_columns);
+            let record = RecordOutput {
+                row_id: "1",
+                kept_columns: &["42".to_string()],
+            };
+            writer.write_record(&record, &[m.clone(), m])?;
+            writer.write_record(
+                &RecordOutput {
+                    row_id: "2",
+                    ..record
+                },
+                &[],
+            )?;
+            writer.finish()?;
+        }
+        let text = String::from_utf8(buf)?;
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let value: serde_json::Value = serde_json::from_str(lines[0])?;
+        assert_eq!(
+            value["metadata"],
+            serde_json::json!(["drug", "fentanyl", "opiate"])
+        );
+        assert_eq!(value["edits"], 1);
+        assert_eq!(value["canonical"], "FENTANYL");
+        assert_eq!(value["AGE"], "42");
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_flags() -> Result<()> {
+        let terms = vec![
+            SearchTerm {
+                term: "COCAINE".to_string(),
+                metadata: Some("drug|stimulant".to_string()),
+                ..Default::default()
+            },
+            SearchTerm {
+                term: "HEROIN".to_string(),
+                metadata: Some("drug|opiate".to_string()),
+                ..Default::default()
+            },
+        ];
+        let fields = vec!["A".to_string(), "B".to_string()];
+        let metadata = terms[1].metadata.clone();
+        let m = SearchOutput {
+            search_field: "B",
+            metadata: &metadata,
+            ..heroin_match()
+        };
+        let kept_columns = vec!["AGE".to_string()];
+        let mut buf = Vec::new();
+        {
+            let mut writer = WideMatchWriter::new(&mut buf, &terms, &fields, &kept_columns)?;
+            let record = RecordOutput {
+                row_id: "1",
+                kept_columns: &["42".to_string()],
+            };
+            writer.write_record(&record, &[m])?;
+            writer.write_record(
+                &RecordOutput {
+                    row_id: "2",
+                    kept_columns: &["".to_string()],
+                },
+                &[],
+            )?;
+            writer.finish()?;
+        }
+        let text = String::from_utf8(buf)?;
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "row_id,AGE,COCAINE,HEROIN,A_matched,B_matched,DRUG_meta,STIMULANT_meta,OPIATE_meta"
+        );
+        assert_eq!(lines[1], "1,42,0,1,0,1,1,0,1");
+        assert_eq!(lines[2], "2,,0,0,0,0,0,0,0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_kept_columns() -> Result<()> {
+        let m = heroin_match();
+        let kept_columns = vec!["AGE".to_string(), "SEX".to_string()];
+        let mut buf = Vec::new();
+        {
+            let mut writer = CsvMatchWriter::new(&mut buf, &kept_columns)?;
+            let record = RecordOutput {
+                row_id: "1",
+                kept_columns: &["42".to_string(), "F".to_string()],
+            };
+            writer.write_record(&record, &[m])?;
+            writer.finish()?;
+        }
+        let text = String::from_utf8(buf)?;
+        assert_eq!(
+            text,
+            "row_id,search_term,matched_term,edits,similarity_score,search_field,metadata,start,end,context,negated,uncertain,canonical,AGE,SEX\n\
+             1,HEROIN,HEROIN,0,1.0,TEXT,,0,6,,false,false,HEROIN,42,F\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        for name in OutputFormat::NAMES {
+            assert!(name.parse::<OutputFormat>().is_ok());
+        }
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use itertools::Itertools;
+use rusqlite::{params, Connection};
+
+use std::path::Path;
+
+use crate::{error::ErrorContext, MatchConfig, MatchWriter, RecordOutput, Result, SearchOutput};
+
+/// Tables created in a new database, existing tables are appended to
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS runs (
+    run_id INTEGER PRIMARY KEY,
+    started_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished_at TEXT,
+    version TEXT NOT NULL,
+    terms_file TEXT NOT NULL,
+    data_file TEXT NOT NULL,
+    max_edits INTEGER NOT NULL,
+    length_window INTEGER NOT NULL,
+    similarity_cutoffs TEXT NOT NULL,
+    distance_metric TEXT NOT NULL,
+    similarity_metric TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS records (
+    run_id INTEGER NOT NULL REFERENCES runs (run_id),
+    row_id TEXT NOT NULL,
+    match_count INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS matches (
+    run_id INTEGER NOT NULL REFERENCES runs (run_id),
+    row_id TEXT NOT NULL,
+    search_term TEXT NOT NULL,
+    matched_term TEXT NOT NULL,
+    edits INTEGER NOT NULL,
+    similarity_score REAL NOT NULL,
+    search_field TEXT NOT NULL,
+    metadata TEXT,
+    "start" INTEGER NOT NULL,
+    "end" INTEGER NOT NULL,
+    context TEXT,
+    negated INTEGER NOT NULL,
+    uncertain INTEGER NOT NULL,
+    canonical TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS matches_run_row ON matches (run_id, row_id);
+"#;
+
+/// Quote an identifier, such as a kept column name, for use in SQL
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The names of the columns of a table
+fn table_columns(connection: &Connection, table: &str) -> Result<Vec<String>> {
+    let read_columns = || {
+        connection
+            .prepare("SELECT name FROM pragma_table_info(?1)")?
+            .query_map([table], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()
+    };
+    read_columns().writer_context(format!("Unable to read {} table columns", table))
+}
+
+/// Writes matches to a SQLite database
+/// Each run is recorded in the `runs` table and all matches and records are tagged with its `run_id`,
+/// so monthly runs can be appended to the same database.
+/// Kept columns are added to the `records` table the first time they are seen.
+/// The run is written in a single transaction which is committed by [MatchWriter::finish].
+#[derive(Debug)]
+pub struct SqliteMatchWriter {
+    connection: Connection,
+    run_id: i64,
+    insert_record: String,
+}
+
+impl SqliteMatchWriter {
+    /// Open or create the database and record the start of a run
+    /// Example:
+    /// ```
+    /// use drug_extraction_cli::{MatchConfig, MatchWriter, RecordOutput, SqliteMatchWriter};
+    ///
+    /// let mut writer = SqliteMatchWriter::open(
+    ///     ":memory:",
+    ///     "search_terms.csv",
+    ///     "records.csv",
+    ///     &MatchConfig::default(),
+    ///     &[],
+    /// )
+    /// .unwrap();
+    /// let record = RecordOutput {
+    ///     row_id: "1",
+    ///     kept_columns: &[],
+    /// };
+    /// writer.write_record(&record, &[]).unwrap();
+    /// writer.finish().unwrap();
+    /// ```
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        terms_file: &str,
+        data_file: &str,
+        match_config: &MatchConfig,
+        kept_columns: &[String],
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let connection = Connection::open(path)
+            .writer_context(format!("Unable to open database {}", path.display()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .writer_context("Unable to create database tables")?;
+        let existing_columns = table_columns(&connection, "records")?;
+        for column in kept_columns {
+            if !existing_columns.contains(column) {
+                connection
+                    .execute(
+                        &format!(
+                            "ALTER TABLE records ADD COLUMN {} TEXT",
+                            quote_identifier(column)
+                        ),
+                        [],
+                    )
+                    .writer_context(format!("Unable to add column {} to records", column))?;
+            }
+        }
+        connection
+            .execute_batch("BEGIN")
+            .writer_context("Unable to start transaction")?;
+        connection
+            .execute(
+                "INSERT INTO runs (version, terms_file, data_file, max_edits, length_window, \
+                 similarity_cutoffs, distance_metric, similarity_metric) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    env!("CARGO_PKG_VERSION"),
+                    terms_file,
+                    data_file,
+                    match_config.max_edits,
+                    match_config.length_window,
+                    match_config.similarity_cutoffs.iter().join(","),
+                    match_config.distance_matcher.name(),
+                    match_config.similarity_matcher.name(),
+                ],
+            )
+            .writer_context("Unable to record run")?;
+        let run_id = connection.last_insert_rowid();
+        let insert_record = format!(
+            "INSERT INTO records (run_id, row_id, match_count{}) VALUES (?1, ?2, ?3{})",
+            kept_columns
+                .iter()
+                .map(|c| format!(", {}", quote_identifier(c)))
+                .join(""),
+            (0..kept_columns.len())
+                .map(|i| format!(", ?{}", i + 4))
+                .join(""),
+        );
+        Ok(SqliteMatchWriter {
+            connection,
+            run_id,
+            insert_record,
+        })
+    }
+
+    /// The id of this run in the `runs` table
+    pub fn run_id(&self) -> i64 {
+        self.run_id
+    }
+}
+
+impl MatchWriter for SqliteMatchWriter {
+    fn write_record(&mut self, record: &RecordOutput, matches: &[SearchOutput]) -> Result<()> {
+        let mut insert_record = self
+            .connection
+            .prepare_cached(&self.insert_record)
+            .writer_context("Unable to prepare records insert")?;
+        let match_count = matches.len();
+        let mut values: Vec<&dyn rusqlite::ToSql> =
+            vec![&self.run_id, &record.row_id, &match_count];
+        values.extend(
+            record
+                .kept_columns
+                .iter()
+                .map(|v| v as &dyn rusqlite::ToSql),
+        );
+        insert_record
+            .execute(values.as_slice())
+            .writer_context("Unable to write record")?;
+
+        let mut insert_match = self
+            .connection
+            .prepare_cached(
+                "INSERT INTO matches (run_id, row_id, search_term, matched_term, edits, \
+                 similarity_score, search_field, metadata, \"start\", \"end\", context, negated, \
+                 uncertain, canonical) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            )
+            .writer_context("Unable to prepare matches insert")?;
+        for m in matches {
+            insert_match
+                .execute(params![
+                    self.run_id,
+                    m.row_id,
+                    m.search_term,
+                    m.matched_term,
+                    m.edits,
+                    m.similarity_score,
+                    m.search_field,
+                    m.metadata,
+                    m.start,
+                    m.end,
+                    m.context,
+                    m.negated,
+                    m.uncertain,
+                    m.canonical,
+                ])
+                .writer_context("Unable to write match")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.connection
+            .execute(
+                "UPDATE runs SET finished_at = CURRENT_TIMESTAMP WHERE run_id = ?1",
+                [self.run_id],
+            )
+            .writer_context("Unable to record end of run")?;
+        self.connection
+            .execute_batch("COMMIT")
+            .writer_context("Unable to commit matches to database")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::heroin_match;
+    use color_eyre::Result;
+
+    #[test]
+    fn test_runs_append_to_database() -> Result<()> {
+        let path = std::env::temp_dir().join("drug_extraction_sqlite_test.db");
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let metadata = Some("drug|opiate".to_string());
+        let m = SearchOutput {
+            metadata: &metadata,
+            ..heroin_match()
+        };
+        for kept_columns in [vec![], vec!["AGE".to_string()]] {
+            let values = vec!["42".to_string(); kept_columns.len()];
+            let mut writer = SqliteMatchWriter::open(
+                &path,
+                "terms.csv",
+                "data.csv",
+                &MatchConfig::default(),
+                &kept_columns,
+            )?;
+            let record = RecordOutput {
+                row_id: "1",
+                kept_columns: &values,
+            };
+            writer.write_record(&record, &[m.clone(), m.clone()])?;
+            writer.write_record(
+                &RecordOutput {
+                    row_id: "2",
+                    ..record
+                },
+                &[],
+            )?;
+            writer.finish()?;
+        }
+
+        let connection = Connection::open(&path)?;
+        let count = |sql: &str| connection.query_row(sql, [], |row| row.get::<_, i64>(0));
+        assert_eq!(count("SELECT COUNT(*) FROM runs")?, 2);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM runs WHERE finished_at IS NOT NULL")?,
+            2
+        );
+        assert_eq!(count("SELECT COUNT(*) FROM matches WHERE run_id = 2")?, 2);
+        assert_eq!(count("SELECT SUM(match_count) FROM records")?, 4);
+        assert_eq!(count("SELECT COUNT(*) FROM records WHERE AGE = '42'")?, 2);
+        drop(connection);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
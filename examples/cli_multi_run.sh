@@ -17,19 +17,17 @@ extract-drugs search \
     -t data/simple_search_terms.csv \
     -d data/cook_records.csv  \
     -c "Primary Cause" \
-    -c "Secondary Cause"
-
-# copy first dataset to new location
-mv output.csv cook_output.csv
+    -c "Secondary Cause" \
+    -o cook_output.csv \
+    --force
 
 # second dataset
 extract-drugs search \
     -t data/simple_search_terms.csv \
     -d data/san_diego_records.csv  \
-    -c "Cause of Death"
-
-# copy second dataset to new location
-mv output.csv san_diego_output.csv
+    -c "Cause of Death" \
+    -o san_diego_output.csv \
+    --force
 
 # combine the two datasets using python stdlib
 python3 -c """
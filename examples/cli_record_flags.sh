@@ -0,0 +1,24 @@
+#! /usr/bin/env bash
+
+# generate binary flags for each search term, search field
+# and metadata tag at the record level (one row per record)
+# this is streamed so it works on datasets that don't fit in memory
+
+# records without any matches are kept (all flags 0) so the output
+# lines up one-to-one with the source data when joined on the id column
+
+extract-drugs search \
+  -t data/search_terms.csv \
+  -d data/cook_records.csv \
+  -c "Primary Cause" \
+  -c "Secondary Cause" \
+  -c "Primary Cause Line A" \
+  -c "Primary Cause Line B" \
+  -c "Primary Cause Line C" \
+  -i "Case Number" \
+  -o data/wide_form_output.csv \
+  --output-shape wide \
+  --force
+
+# print a couple of sample records
+tail -n 2 data/wide_form_output.csv